@@ -0,0 +1,182 @@
+// This file is part of cloudbit-software.
+//
+// cloudbit-software - an alternative software for the littleBits cloudBit.
+//
+// Copyright (C) 2024 littleBitsman
+//
+// cloudbit-software is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// cloudbit-software is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see https://www.gnu.org/licenses/.
+
+//! Challenge-response device authentication
+//!
+//! The MAC-Address and CB-Id headers (and IDENTIFY) only say which device
+//! this claims to be. A server that wants proof answers IDENTIFY with
+//! CHALLENGE (0x8) instead of HELLO:
+//!
+//! ```json
+//! { "opcode": 8, "nonce": "<any string>" }
+//! ```
+//!
+//! and the cloudBit replies with AUTH (0x9):
+//!
+//! ```json
+//! { "opcode": 9, "algorithm": "hmac-sha256", "hmac": "<hex>", "nonce": "<hex>" }
+//! ```
+//!
+//! `hmac` is the HMAC-SHA256 of the server's nonce keyed with the device
+//! secret at [`Config::auth_secret_path`]. `nonce` is a fresh one from the
+//! cloudBit; the server can prove it knows the secret too by putting the
+//! HMAC of that in HELLO as `hmac`. A wrong one always hangs up. Signed
+//! commands carry it too, so they can't be replayed on another connection.
+//!
+//! Servers that never send CHALLENGE keep working (legacy mode) until
+//! [`Config::auth_legacy`] is turned off; then the server has to have
+//! proven itself in HELLO or the connection is dropped.
+
+use crate::config::{self, Config};
+use data_encoding::{HEXLOWER, HEXLOWER_PERMISSIVE};
+use rand::{thread_rng, RngCore};
+use ring::hmac::{self, Key, HMAC_SHA256};
+use serde_json::{json as serde_json, Value as JsonValue};
+use std::{fs::read, io::ErrorKind as IoErrorKind, sync::OnceLock};
+
+/// Algorithms IDENTIFY lists when there's a secret to use them with.
+pub const ALGORITHMS: &[&str] = &["hmac-sha256"];
+
+static SECRET: OnceLock<Option<Key>> = OnceLock::new();
+
+/// The device secret (the file's contents, without trailing whitespace),
+/// read the first time it's needed.
+fn secret() -> Option<&'static Key> {
+    SECRET
+        .get_or_init(|| {
+            let path = &config::get().auth_secret_path;
+            match read(path) {
+                Ok(mut bytes) => {
+                    while bytes.last().is_some_and(u8::is_ascii_whitespace) {
+                        bytes.pop();
+                    }
+                    if bytes.is_empty() {
+                        eprintln!("device secret {path} is empty, not using it");
+                        return None;
+                    }
+                    Some(Key::new(HMAC_SHA256, &bytes))
+                }
+                Err(err) => {
+                    if err.kind() != IoErrorKind::NotFound {
+                        eprintln!("Error while reading {path}: {err}");
+                    }
+                    None
+                }
+            }
+        })
+        .as_ref()
+}
+
+/// The algorithms to list in IDENTIFY (none without a secret).
+pub fn algorithms() -> &'static [&'static str] {
+    if secret().is_some() {
+        ALGORITHMS
+    } else {
+        &[]
+    }
+}
+
+/// Whether `tag` is the hex HMAC-SHA256 of `message` keyed with the device
+/// secret (never, without one).
+pub fn verify(message: &[u8], tag: &str) -> bool {
+    let Some(key) = secret() else {
+        return false;
+    };
+    HEXLOWER_PERMISSIVE
+        .decode(tag.as_bytes())
+        .is_ok_and(|tag| hmac::verify(key, message, &tag).is_ok())
+}
+
+/// The authentication state of one connection attempt.
+#[derive(Default)]
+pub struct Handshake {
+    /// The nonce sent in AUTH, for checking the server's HMAC in HELLO (and
+    /// signed commands later on, see [`crate::policy`])
+    nonce: Option<String>,
+}
+
+impl Handshake {
+    /// If `packet` is a CHALLENGE, the AUTH packet to answer it with.
+    pub fn answer(&mut self, packet: &JsonValue) -> Option<JsonValue> {
+        if packet["opcode"].as_u64() != Some(0x8) {
+            return None;
+        }
+
+        let Some(key) = secret() else {
+            eprintln!("server sent CHALLENGE, but there's no device secret");
+            return Some(serde_json!({
+                "opcode": 0x9,
+                "error": "no device secret"
+            }));
+        };
+        let Some(challenge) = packet["nonce"].as_str() else {
+            eprintln!("CHALLENGE without a nonce");
+            return Some(serde_json!({
+                "opcode": 0x9,
+                "error": "missing nonce"
+            }));
+        };
+
+        let mut nonce = [0; 16];
+        thread_rng().fill_bytes(&mut nonce);
+        let nonce = HEXLOWER.encode(&nonce);
+        let tag = hmac::sign(key, challenge.as_bytes());
+        let answer = serde_json!({
+            "opcode": 0x9,
+            "algorithm": ALGORITHMS[0],
+            "hmac": HEXLOWER.encode(tag.as_ref()),
+            "nonce": nonce
+        });
+        self.nonce = Some(nonce);
+        Some(answer)
+    }
+
+    /// Checks the first packet after the handshake (`None` if the server
+    /// didn't send anything in time). `Ok` has the nonce sent in AUTH, if
+    /// there was a CHALLENGE; `Err` is why the connection should be dropped.
+    pub fn finish(
+        self,
+        packet: Option<&JsonValue>,
+        config: &Config,
+    ) -> Result<Option<String>, String> {
+        let proof = packet
+            .filter(|packet| packet["opcode"].as_u64() == Some(0x6))
+            .and_then(|hello| hello["hmac"].as_str());
+
+        let verified = match (proof, &self.nonce) {
+            (Some(proof), Some(nonce)) => {
+                if !verify(nonce.as_bytes(), proof) {
+                    return Err(String::from("server sent the wrong HMAC in HELLO"));
+                }
+                true
+            }
+            (Some(_), None) => {
+                return Err(String::from(
+                    "server sent an HMAC in HELLO without a CHALLENGE",
+                ))
+            }
+            (None, _) => false,
+        };
+
+        if !verified && !config.auth_legacy {
+            return Err(String::from(
+                "server didn't authenticate (and legacy mode is off)",
+            ));
+        }
+        Ok(self.nonce)
+    }
+}
@@ -0,0 +1,236 @@
+// This file is part of cloudbit-software.
+//
+// cloudbit-software - an alternative software for the littleBits cloudBit.
+//
+// Copyright (C) 2024 littleBitsman
+//
+// cloudbit-software is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// cloudbit-software is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see https://www.gnu.org/licenses/.
+
+//! Offline buffer for INPUT events
+//!
+//! While the cloudBit can't reach a server, input changes are kept here
+//! (oldest ones are dropped once [`Config::backlog_max_events`] is reached)
+//! and replayed after the next IDENTIFY.
+//!
+//! The buffer lives in memory and is written to [`Config::backlog_path`]
+//! every [`Config::backlog_flush_secs`] if it changed (see [`start`]), so it
+//! survives a restart without writing to the SD card on every single change. The file starts
+//! with the [boot ID](clock::boot_id) (36 bytes, zeroes if unknown), then
+//! each event is a fixed 18 byte record: the value (u16, a raw ADC count),
+//! `mono_ms` and `timestamp` (u64s, `u64::MAX` if missing), all
+//! little-endian. Events saved before a reboot lose their `mono_ms` when
+//! they're loaded, since the monotonic clock started over.
+
+use crate::{
+    clock,
+    config::{self, Config},
+};
+use std::{
+    collections::VecDeque,
+    fs::{read, remove_file, rename, write},
+    io::{ErrorKind as IoErrorKind, Result as IoResult},
+    sync::{Mutex, MutexGuard},
+    time::Duration,
+};
+use tokio::{spawn, task::spawn_blocking, time::interval};
+
+const BOOT_ID_SIZE: usize = 36;
+const RECORD_SIZE: usize = 18;
+/// A missing time, as stored in a record
+const MISSING: u64 = u64::MAX;
+
+static BACKLOG: Mutex<Backlog> = Mutex::new(Backlog {
+    events: VecDeque::new(),
+    dirty: false,
+});
+/// Held while writing the file, so two flushes can't finish out of order
+static WRITING: Mutex<()> = Mutex::new(());
+
+#[derive(Clone, Copy)]
+pub struct Event {
+    /// Raw ADC count
+    pub value: u16,
+    /// Milliseconds since boot (`None` if it was before a reboot)
+    pub mono_ms: Option<u64>,
+    /// Milliseconds since the Unix epoch (`None` if the clock wasn't synced)
+    pub timestamp: Option<u64>,
+}
+
+impl Event {
+    /// An event for `value` happening right now.
+    pub fn now(value: u16) -> Self {
+        Self {
+            value,
+            mono_ms: Some(clock::monotonic_ms()),
+            timestamp: clock::synced_wall_ms(),
+        }
+    }
+}
+
+/// The boot ID to store (zeroes if there isn't one).
+fn boot_id() -> [u8; BOOT_ID_SIZE] {
+    let mut id = [0; BOOT_ID_SIZE];
+    if let Some(boot_id) = clock::boot_id().filter(|v| v.len() == BOOT_ID_SIZE) {
+        id.copy_from_slice(boot_id.as_bytes());
+    }
+    id
+}
+
+struct Backlog {
+    events: VecDeque<Event>,
+    /// Whether `events` has changed since it was last written to disk
+    dirty: bool,
+}
+
+impl Backlog {
+    fn trim(&mut self, config: &Config) {
+        let excess = self.events.len().saturating_sub(config.backlog_max_events);
+        self.events.drain(..excess);
+    }
+
+    /// The file contents if it needs writing (`Some(None)` if it should be
+    /// removed), marking the buffer clean.
+    fn snapshot(&mut self) -> Option<Option<Vec<u8>>> {
+        if !self.dirty {
+            return None;
+        }
+        self.dirty = false;
+
+        if self.events.is_empty() {
+            return Some(None);
+        }
+
+        let mut bytes = Vec::with_capacity(BOOT_ID_SIZE + self.events.len() * RECORD_SIZE);
+        bytes.extend_from_slice(&boot_id());
+        for event in &self.events {
+            bytes.extend_from_slice(&event.value.to_le_bytes());
+            bytes.extend_from_slice(&event.mono_ms.unwrap_or(MISSING).to_le_bytes());
+            bytes.extend_from_slice(&event.timestamp.unwrap_or(MISSING).to_le_bytes());
+        }
+        Some(Some(bytes))
+    }
+}
+
+/// Writes (or removes) the file, if the buffer changed. This blocks, so it
+/// runs on the blocking pool.
+fn write_out(config: &Config) -> IoResult<()> {
+    let _writing = WRITING.lock().unwrap_or_else(|err| err.into_inner());
+    // Only hold the buffer's lock for the copy, not the file I/O
+    let snapshot = lock().snapshot();
+    let Some(snapshot) = snapshot else {
+        return Ok(());
+    };
+    let Some(bytes) = snapshot else {
+        return match remove_file(&config.backlog_path) {
+            Err(err) if err.kind() != IoErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        };
+    };
+
+    // Write a copy and then swap it in, so a power cut mid-write
+    // leaves the old file instead of half of a new one.
+    let temp = format!("{}.tmp", config.backlog_path);
+    write(&temp, bytes)?;
+    rename(temp, &config.backlog_path)
+}
+
+fn lock() -> MutexGuard<'static, Backlog> {
+    BACKLOG.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Loads events left on disk by a previous run.
+pub fn load() {
+    let config = config::get();
+    let bytes = match read(&config.backlog_path) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            if err.kind() != IoErrorKind::NotFound {
+                eprintln!("failed to read offline buffer: {err}");
+            }
+            return;
+        }
+    };
+
+    if bytes.len() < BOOT_ID_SIZE {
+        return;
+    }
+    let (id, records) = bytes.split_at(BOOT_ID_SIZE);
+    let same_boot = id != [0; BOOT_ID_SIZE] && *id == boot_id();
+    let time = |bytes: &[u8]| {
+        Some(u64::from_le_bytes(bytes.try_into().unwrap())).filter(|v| *v != MISSING)
+    };
+
+    let mut backlog = lock();
+    for record in records.chunks_exact(RECORD_SIZE) {
+        backlog.events.push_back(Event {
+            value: u16::from_le_bytes(record[..2].try_into().unwrap()),
+            mono_ms: time(&record[2..10]).filter(|_| same_boot),
+            timestamp: time(&record[10..]),
+        });
+    }
+    backlog.trim(config);
+
+    if !backlog.events.is_empty() {
+        eprintln!(
+            "{} buffered input events from last run",
+            backlog.events.len()
+        );
+    }
+}
+
+/// Writes the buffer to disk every [`Config::backlog_flush_secs`] while it
+/// has unsaved changes.
+pub fn start() {
+    let period = Duration::from_secs(config::get().backlog_flush_secs.max(1));
+    spawn(async move {
+        let mut timer = interval(period);
+        loop {
+            timer.tick().await;
+            flush().await;
+        }
+    });
+}
+
+/// Adds an event (to be written to disk by the next timed flush).
+pub fn push(event: Event) {
+    let mut backlog = lock();
+    backlog.events.push_back(event);
+    backlog.trim(config::get());
+    backlog.dirty = true;
+}
+
+/// Takes every buffered event out, oldest first.
+pub fn take() -> Vec<Event> {
+    let mut backlog = lock();
+    if !backlog.events.is_empty() {
+        backlog.dirty = true;
+    }
+    backlog.events.drain(..).collect()
+}
+
+/// Puts events from [`take`] back in front (when sending them failed).
+pub fn restore(events: Vec<Event>) {
+    let mut backlog = lock();
+    for event in events.into_iter().rev() {
+        backlog.events.push_front(event);
+    }
+    backlog.trim(config::get());
+    backlog.dirty = true;
+}
+
+/// Writes the buffer to disk now if it changed (or removes the file if the
+/// buffer is empty).
+pub async fn flush() {
+    if let Ok(Err(err)) = spawn_blocking(|| write_out(config::get())).await {
+        eprintln!("failed to write offline buffer: {err}");
+    }
+}
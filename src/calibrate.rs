@@ -0,0 +1,169 @@
+// This file is part of cloudbit-software.
+//
+// cloudbit-software - an alternative software for the littleBits cloudBit.
+//
+// Copyright (C) 2024 littleBitsman
+//
+// cloudbit-software is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// cloudbit-software is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see https://www.gnu.org/licenses/.
+
+//! Calibrating the input and output
+//!
+//! Each step measures something against a known reference and saves the
+//! result (see [`calibration`]):
+//! - `input_low`: the input at 0 V (nothing connected, or a bit at 0)
+//! - `input_high`: the input at 5 V (a bit at full, like a button held down)
+//! - `output`: the output wired straight back into the input (so do the
+//!   input steps first), swept from one end to the other
+//! - `reset`: back to the defaults
+//!
+//! These come from the `0xF7` developer opcode or `cloud_client calibrate
+//! <step>`.
+
+use crate::{
+    clock,
+    hardware::{
+        self, adc,
+        calibration::{self, Calibration},
+    },
+};
+use serde_json::{json as serde_json, to_string_pretty, Value as JsonValue};
+use std::{thread::sleep, time::Duration};
+
+/// Reads averaged for one measurement
+const READS: u32 = 64;
+/// How far apart (in raw counts) 0 V and 5 V have to be for the input to be
+/// believable
+const INPUT_MARGIN: u16 = 100;
+/// Samples the output sweep goes through
+const SWEEP: [i16; 9] = [-32768, -24577, -16386, -8195, -4, 8187, 16378, 24569, 32760];
+/// How long the output gets to settle before it's measured
+const SETTLE: Duration = Duration::from_millis(20);
+
+pub const STEPS: [&str; 4] = ["input_low", "input_high", "output", "reset"];
+
+/// Runs a step (blocking for a while), returning the calibration in use
+/// afterwards.
+pub fn run(step: &str) -> Result<Calibration, String> {
+    let mut new = calibration::get();
+    match step {
+        "input_low" => new.input.min = measure(),
+        "input_high" => new.input.max = measure(),
+        "output" => new.output = sweep()?,
+        "reset" => {
+            calibration::reset().map_err(|err| format!("couldn't reset: {err}"))?;
+            return Ok(calibration::get());
+        }
+        _ => {
+            return Err(format!(
+                "unknown step (must be one of {})",
+                STEPS.join(", ")
+            ))
+        }
+    }
+
+    if new.input.min.saturating_add(INPUT_MARGIN) > new.input.max {
+        return Err(format!(
+            "input reads {} at 0 V and {} at 5 V, which can't be right",
+            new.input.min, new.input.max
+        ));
+    }
+    new.calibrated_at = clock::synced_wall_ms();
+    calibration::set(new).map_err(|err| format!("couldn't save: {err}"))?;
+    Ok(new)
+}
+
+/// The reply to `0xF7`: `0xF8` with the calibration in use (and an `error`
+/// if the step failed).
+pub fn reply(result: Result<Calibration, String>) -> JsonValue {
+    let mut packet = serde_json!({ "opcode": 0xF8 });
+    match result {
+        Ok(calibration) => packet["calibration"] = serde_json!(calibration),
+        Err(err) => {
+            packet["calibration"] = serde_json!(calibration::get());
+            packet["error"] = err.into();
+        }
+    }
+    packet
+}
+
+/// `cloud_client calibrate <step>`: runs a step and prints the reply,
+/// returning the exit code. The service should be stopped first, or it'll
+/// be using the hardware at the same time.
+pub fn command(step: &str) -> i32 {
+    let hardware = hardware::init();
+    if !hardware.adc || !hardware.dac {
+        eprintln!("the ADC and DAC have to work to calibrate");
+        return 1;
+    }
+    let result = run(step);
+    let code = i32::from(result.is_err());
+    println!("{}", to_string_pretty(&reply(result)).unwrap());
+    code
+}
+
+/// The raw input, averaged over [`READS`] reads.
+fn measure() -> u16 {
+    let hardware = hardware::get();
+    let total: u32 = (0..READS).map(|_| u32::from(hardware.read_input())).sum();
+    ((total + READS / 2) / READS) as u16
+}
+
+/// Sweeps the output through [`SWEEP`] and fits a line through what the
+/// input (scaled with its calibration) reads back. The output is put back
+/// the way it was afterwards.
+fn sweep() -> Result<calibration::OutputCalibration, String> {
+    let hardware = hardware::get();
+    let input = calibration::get().input;
+    let previous = hardware.read_output_sample();
+    let mut points = Vec::new();
+    for sample in SWEEP {
+        hardware.set_output_sample(sample);
+        sleep(SETTLE);
+        let raw = measure();
+        // The ends of the input range are clamped, so they say nothing
+        if raw > input.min && raw < input.max {
+            points.push((f64::from(sample), f64::from(adc::scale(raw))));
+        }
+    }
+    if let Some(sample) = previous {
+        hardware.set_output_sample(sample);
+    }
+
+    if points.len() < 3 {
+        return Err(String::from(
+            "the input barely followed the output (is the output wired to the input?)",
+        ));
+    }
+
+    // Least squares: value = slope * sample + intercept
+    let n = points.len() as f64;
+    let (sum_x, sum_y) = points
+        .iter()
+        .fold((0.0, 0.0), |(x, y), p| (x + p.0, y + p.1));
+    let (mean_x, mean_y) = (sum_x / n, sum_y / n);
+    let (mut sxx, mut sxy) = (0.0, 0.0);
+    for (x, y) in &points {
+        sxx += (x - mean_x) * (x - mean_x);
+        sxy += (x - mean_x) * (y - mean_y);
+    }
+    let slope = sxy / sxx;
+    if !slope.is_finite() || slope <= 0.0 {
+        return Err(String::from("the input doesn't go up with the output"));
+    }
+    let intercept = mean_y - slope * mean_x;
+
+    // Turned around, that's the sample for a value
+    Ok(calibration::OutputCalibration {
+        gain: 1.0 / slope,
+        offset: -intercept / slope,
+    })
+}
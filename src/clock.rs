@@ -0,0 +1,131 @@
+// This file is part of cloudbit-software.
+//
+// cloudbit-software - an alternative software for the littleBits cloudBit.
+//
+// Copyright (C) 2024 littleBitsman
+//
+// cloudbit-software is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// cloudbit-software is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see https://www.gnu.org/licenses/.
+
+//! Timestamps for events
+//!
+//! The cloudBit has no battery-backed clock, so its wall clock starts out
+//! in 1970 (or wherever it was last saved) until NTP sets it, and can jump
+//! when that happens. Events are stamped with the monotonic clock instead
+//! (`mono_ms`: milliseconds since boot, which never jumps), plus the wall
+//! clock (`timestamp`: milliseconds since the Unix epoch) only while the
+//! kernel says it's synced.
+//!
+//! To line `mono_ms` up with its own clock, the server gets a reference
+//! point ([`reference`]: both clocks read at the same moment) on the first
+//! INPUT of every connection. The monotonic clock restarts at 0 when the
+//! cloudBit reboots, which always means a new connection.
+
+use libc::{adjtimex, clock_gettime, timespec, timex, CLOCK_MONOTONIC, STA_UNSYNC, TIME_ERROR};
+use serde_json::{json as serde_json, Value as JsonValue};
+use std::{
+    fs::read_to_string,
+    mem::zeroed,
+    sync::OnceLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+static BOOT_ID: OnceLock<Option<String>> = OnceLock::new();
+
+/// Time since boot (`CLOCK_MONOTONIC`).
+pub fn monotonic() -> Duration {
+    let mut now = timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `now` is a valid timespec to write to, and CLOCK_MONOTONIC
+    // always exists on Linux
+    unsafe {
+        clock_gettime(CLOCK_MONOTONIC, &mut now);
+    }
+    Duration::new(now.tv_sec as u64, now.tv_nsec as u32)
+}
+
+/// Milliseconds since boot.
+pub fn monotonic_ms() -> u64 {
+    monotonic().as_millis() as u64
+}
+
+/// The kernel's random ID for this boot, for telling whether a saved
+/// monotonic time is from before a reboot.
+pub fn boot_id() -> Option<&'static str> {
+    BOOT_ID
+        .get_or_init(|| {
+            read_to_string("/proc/sys/kernel/random/boot_id")
+                .ok()
+                .map(|id| id.trim().to_string())
+        })
+        .as_deref()
+}
+
+/// Milliseconds since the Unix epoch, whether or not the clock is right.
+fn wall_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |v| v.as_millis() as u64)
+}
+
+/// Whether the kernel thinks the wall clock is synced (NTP has set it and
+/// is keeping it right).
+pub fn is_synced() -> bool {
+    // SAFETY: all zeroes is a valid `timex` (it's plain integers), and with
+    // `modes` 0 adjtimex only reads the clock's state into it
+    let mut state: timex = unsafe { zeroed() };
+    // SAFETY: `state` is a valid timex to read from and write to
+    let result = unsafe { adjtimex(&mut state) };
+    result >= 0 && result != TIME_ERROR && state.status & STA_UNSYNC == 0
+}
+
+/// Milliseconds since the Unix epoch, if the wall clock is synced.
+pub fn synced_wall_ms() -> Option<u64> {
+    is_synced().then(wall_ms)
+}
+
+/// Adds `mono_ms` (and `timestamp` if the wall clock is synced) to an
+/// event packet, as of now.
+pub fn stamp(mut packet: JsonValue) -> JsonValue {
+    packet["mono_ms"] = monotonic_ms().into();
+    if let Some(timestamp) = synced_wall_ms() {
+        packet["timestamp"] = timestamp.into();
+    }
+    packet
+}
+
+/// Both clocks read at the same moment, and whether the wall clock can be
+/// trusted.
+pub fn reference() -> JsonValue {
+    serde_json!({
+        "mono_ms": monotonic_ms(),
+        "timestamp": wall_ms(),
+        "synced": is_synced()
+    })
+}
+
+/// Puts the [`reference`] on the first INPUT sent on a connection.
+#[derive(Default)]
+pub struct Reference {
+    sent: bool,
+}
+
+impl Reference {
+    pub fn add(&mut self, mut packet: JsonValue) -> JsonValue {
+        if !self.sent && packet["opcode"] == 0x1 {
+            packet["clock"] = reference();
+            self.sent = true;
+        }
+        packet
+    }
+}
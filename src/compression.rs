@@ -0,0 +1,749 @@
+// This file is part of cloudbit-software.
+//
+// cloudbit-software - an alternative software for the littleBits cloudBit.
+//
+// Copyright (C) 2024 littleBitsman
+//
+// cloudbit-software is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// cloudbit-software is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see https://www.gnu.org/licenses/.
+
+//! permessage-deflate (RFC 7692) for the WebSocket to the server
+//!
+//! tungstenite doesn't support any extensions (no version does yet, and
+//! it drops the connection on a compressed frame), so this sits between it
+//! and the socket instead: [`Compressed`] watches the handshake go by, and
+//! if the server accepted the offer from [`offer`], inflates compressed
+//! messages from the server and deflates outgoing ones (see [`deflate`]).
+//! tungstenite only ever sees plain frames, and still does all the other
+//! checks on them.
+//!
+//! [`Config::deflate_window_bits`] bounds the server's window. miniz_oxide
+//! always compresses with a 32 KiB one, so the cloudBit's own window can't
+//! be limited and isn't offered. Servers that don't accept the offer get
+//! plain frames, as if it was never made.
+//!
+//! [`deflate`]: crate::deflate
+
+use crate::{
+    config::{self, Config},
+    deflate::{Compressor, Decompressor},
+};
+use httparse::{Response, Status, EMPTY_HEADER};
+use std::{
+    io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult},
+    mem::take,
+    ops::RangeInclusive,
+    pin::Pin,
+    str::from_utf8,
+    task::{ready, Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Window sizes that can be asked for (zlib can't do 8 for raw DEFLATE)
+const WINDOW_BITS_RANGE: RangeInclusive<u8> = 9..=15;
+
+/// The biggest message a compressed one from the server can inflate to
+const MAX_MESSAGE_SIZE: usize = 1 << 20;
+
+/// The biggest frame, and compressed message, let through. These are
+/// tungstenite's own limits, so plain frames aren't held to anything
+/// stricter than without compression.
+const MAX_FRAME_SIZE: usize = 16 << 20;
+const MAX_COMPRESSED_SIZE: usize = 64 << 20;
+
+/// How much outgoing data is buffered before writes wait for the socket
+const WRITE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// The window size to ask the server for, if compression is on at all.
+fn window_bits(config: &Config) -> Option<u8> {
+    if !config.deflate {
+        return None;
+    }
+    if WINDOW_BITS_RANGE.contains(&config.deflate_window_bits) {
+        Some(config.deflate_window_bits)
+    } else {
+        eprintln!(
+            "deflate_window_bits must be from 9 to 15, not {}; not compressing",
+            config.deflate_window_bits
+        );
+        None
+    }
+}
+
+/// The `Sec-WebSocket-Extensions` header to offer, if compression is on.
+pub fn offer() -> Option<String> {
+    window_bits(config::get())
+        .map(|bits| format!("permessage-deflate; server_max_window_bits={bits}"))
+}
+
+fn invalid(message: String) -> IoError {
+    IoError::new(IoErrorKind::InvalidData, message)
+}
+
+/// Reads what the server agreed to in its handshake response. `None` means
+/// no compression.
+fn negotiate(response: &[u8], offered_bits: u8) -> IoResult<Option<Codec>> {
+    let mut headers = [EMPTY_HEADER; 64];
+    let mut parsed = Response::new(&mut headers);
+    // Anything odd here is left for tungstenite to complain about
+    if !matches!(parsed.parse(response), Ok(Status::Complete(_))) || parsed.code != Some(101) {
+        return Ok(None);
+    }
+
+    let mut agreed = None;
+    let extensions = parsed
+        .headers
+        .iter()
+        .filter(|header| header.name.eq_ignore_ascii_case("Sec-WebSocket-Extensions"))
+        .flat_map(|header| from_utf8(header.value).unwrap_or_default().split(','))
+        .filter(|extension| !extension.trim().is_empty());
+
+    for extension in extensions {
+        let mut params = extension.split(';').map(str::trim);
+        if params.next() != Some("permessage-deflate") || agreed.is_some() {
+            return Err(invalid(format!(
+                "server picked an extension that wasn't offered: {extension}"
+            )));
+        }
+
+        let (mut client_takeover, mut server_takeover) = (true, true);
+        for param in params {
+            let (key, value) = match param.split_once('=') {
+                Some((key, value)) => (key.trim(), Some(value.trim().trim_matches('"'))),
+                None => (param, None),
+            };
+            match key {
+                "server_max_window_bits" => {
+                    let bits = value
+                        .and_then(|v| v.parse::<u8>().ok())
+                        .filter(|v| (8..=15).contains(v))
+                        .ok_or_else(|| {
+                            invalid(format!("bad permessage-deflate parameter {param}"))
+                        })?;
+                    // RFC 7692 section 7.1.2.1: never more than was offered
+                    if bits > offered_bits {
+                        return Err(invalid(format!(
+                            "server picked a bigger window than offered: {param}"
+                        )));
+                    }
+                }
+                "client_max_window_bits" => {
+                    return Err(invalid(format!(
+                        "server limited the cloudBit's window, which wasn't offered: {param}"
+                    )))
+                }
+                "client_no_context_takeover" => client_takeover = false,
+                "server_no_context_takeover" => server_takeover = false,
+                _ => {
+                    return Err(invalid(format!(
+                        "unknown permessage-deflate parameter {param}"
+                    )))
+                }
+            }
+        }
+
+        eprintln!("using permessage-deflate ({extension})");
+        agreed = Some(Codec {
+            compressor: Compressor::new(client_takeover),
+            decompressor: Decompressor::new(server_takeover),
+        });
+    }
+    Ok(agreed)
+}
+
+struct Codec {
+    compressor: Compressor,
+    decompressor: Decompressor,
+}
+
+/// The parts of a frame header this cares about.
+struct FrameHeader {
+    fin: bool,
+    rsv1: bool,
+    /// RSV2 or RSV3, which nothing uses
+    other_rsv: bool,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    /// Size of the header itself
+    size: usize,
+    payload_size: usize,
+}
+
+impl FrameHeader {
+    const CONTINUATION: u8 = 0x0;
+    const TEXT: u8 = 0x1;
+    const BINARY: u8 = 0x2;
+
+    /// Reads the header at the start of `bytes`. `None` means the whole
+    /// frame isn't there yet.
+    fn parse(bytes: &[u8]) -> IoResult<Option<Self>> {
+        let Some(&[first, second]) = bytes.get(..2) else {
+            return Ok(None);
+        };
+        let (payload_size, mut size) = match second & 0x7F {
+            126 => match bytes.get(2..4) {
+                Some(v) => (usize::from(u16::from_be_bytes([v[0], v[1]])), 4),
+                None => return Ok(None),
+            },
+            127 => match bytes.get(2..10) {
+                Some(v) => (
+                    usize::try_from(u64::from_be_bytes(v.try_into().unwrap()))
+                        .unwrap_or(usize::MAX),
+                    10,
+                ),
+                None => return Ok(None),
+            },
+            length => (usize::from(length), 2),
+        };
+        if payload_size > MAX_FRAME_SIZE {
+            return Err(invalid(format!("{payload_size} byte frame is too big")));
+        }
+
+        let mask = if second & 0x80 != 0 {
+            let Some(mask) = bytes.get(size..size + 4) else {
+                return Ok(None);
+            };
+            size += 4;
+            Some(mask.try_into().unwrap())
+        } else {
+            None
+        };
+
+        if bytes.len() < size + payload_size {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            fin: first & 0x80 != 0,
+            rsv1: first & 0x40 != 0,
+            other_rsv: first & 0x30 != 0,
+            opcode: first & 0x0F,
+            mask,
+            size,
+            payload_size,
+        }))
+    }
+
+    fn is_control(&self) -> bool {
+        self.opcode & 0x8 != 0
+    }
+
+    fn is_data(&self) -> bool {
+        matches!(self.opcode, Self::TEXT | Self::BINARY)
+    }
+}
+
+fn apply_mask(data: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+/// A whole (FIN) frame.
+fn frame(first: u8, mask: Option<[u8; 4]>, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![first];
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        length @ 0..=125 => frame.push(mask_bit | length as u8),
+        length @ 126..=0xFFFF => {
+            frame.push(mask_bit | 126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(mask_bit | 127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+
+    if let Some(mask) = mask {
+        frame.extend_from_slice(&mask);
+    }
+    let start = frame.len();
+    frame.extend_from_slice(payload);
+    if let Some(mask) = mask {
+        apply_mask(&mut frame[start..], mask);
+    }
+    frame
+}
+
+fn find_header_end(bytes: &[u8]) -> Option<usize> {
+    bytes
+        .windows(4)
+        .position(|v| v == b"\r\n\r\n")
+        .map(|v| v + 4)
+}
+
+/// A stream that adds permessage-deflate under a WebSocket client.
+pub struct Compressed<S> {
+    inner: S,
+    /// The window size offered (`None` = nothing was offered)
+    offered_bits: Option<u8>,
+    /// Set once the handshake response has gone by, if the server accepted
+    codec: Option<Codec>,
+    request_sent: bool,
+    response_seen: bool,
+    /// Bytes from the socket that haven't been looked at yet
+    incoming: Vec<u8>,
+    /// Bytes ready for tungstenite to read
+    readable: Vec<u8>,
+    /// The compressed message being put together (opcode and payload)
+    message: Option<(u8, Vec<u8>)>,
+    /// Bytes from tungstenite that haven't been looked at yet
+    outgoing: Vec<u8>,
+    /// Bytes ready to be written to the socket
+    writable: Vec<u8>,
+}
+
+impl<S> Compressed<S> {
+    pub fn new(inner: S) -> Self {
+        Self::with_offer(inner, window_bits(config::get()))
+    }
+
+    /// Like [`new`](Self::new), with the window size that was offered.
+    fn with_offer(inner: S, offered_bits: Option<u8>) -> Self {
+        Self {
+            inner,
+            offered_bits,
+            codec: None,
+            request_sent: false,
+            response_seen: false,
+            incoming: Vec::new(),
+            readable: Vec::new(),
+            message: None,
+            outgoing: Vec::new(),
+            writable: Vec::new(),
+        }
+    }
+
+    /// Moves what can be moved from `incoming` to `readable`, inflating
+    /// compressed messages on the way.
+    fn process_incoming(&mut self) -> IoResult<()> {
+        if !self.response_seen {
+            let Some(end) = find_header_end(&self.incoming) else {
+                return Ok(());
+            };
+            if let Some(bits) = self.offered_bits {
+                self.codec = negotiate(&self.incoming[..end], bits)?;
+            }
+            self.response_seen = true;
+            self.readable.extend(self.incoming.drain(..end));
+        }
+
+        let Some(codec) = &mut self.codec else {
+            self.readable.append(&mut self.incoming);
+            return Ok(());
+        };
+
+        while let Some(header) = FrameHeader::parse(&self.incoming)? {
+            // Frames from the server are never masked (RFC 6455 section
+            // 5.1), and RSV1 only goes on the first frame of a data message
+            // (RFC 7692 section 6.1)
+            if header.mask.is_some() {
+                return Err(invalid(String::from("masked frame from the server")));
+            }
+            if header.other_rsv
+                || header.rsv1
+                    && (header.is_control() || header.opcode == FrameHeader::CONTINUATION)
+            {
+                return Err(invalid(format!(
+                    "reserved bits set on a frame with opcode {}",
+                    header.opcode
+                )));
+            }
+
+            let bytes: Vec<u8> = self
+                .incoming
+                .drain(..header.size + header.payload_size)
+                .collect();
+            let payload = bytes[header.size..].to_vec();
+
+            match &mut self.message {
+                _ if header.is_control() => {
+                    self.readable.extend(bytes);
+                    continue;
+                }
+                None if header.is_data() && header.rsv1 => {
+                    self.message = Some((header.opcode, payload));
+                }
+                Some((_, message)) if header.opcode == FrameHeader::CONTINUATION => {
+                    if message.len() + payload.len() > MAX_COMPRESSED_SIZE {
+                        return Err(invalid(String::from("compressed message is too big")));
+                    }
+                    message.extend(payload);
+                }
+                // Uncompressed messages go through as they are
+                None => {
+                    self.readable.extend(bytes);
+                    continue;
+                }
+                Some(_) => {
+                    return Err(invalid(String::from(
+                        "new message before the last one ended",
+                    )))
+                }
+            }
+
+            if header.fin {
+                let (opcode, message) = self.message.take().unwrap();
+                let inflated = codec
+                    .decompressor
+                    .decompress(&message, MAX_MESSAGE_SIZE)
+                    .map_err(|err| invalid(format!("bad compressed message: {err}")))?;
+                self.readable.extend(frame(0x80 | opcode, None, &inflated));
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves what can be moved from `outgoing` to `writable`, deflating
+    /// whole messages on the way.
+    fn process_outgoing(&mut self) -> IoResult<()> {
+        if !self.request_sent {
+            let Some(end) = find_header_end(&self.outgoing) else {
+                return Ok(());
+            };
+            self.request_sent = true;
+            self.writable.extend(self.outgoing.drain(..end));
+        }
+
+        // Frames only go out after the response, so this is settled by now
+        let Some(codec) = &mut self.codec else {
+            self.writable.append(&mut self.outgoing);
+            return Ok(());
+        };
+
+        while let Some(header) = FrameHeader::parse(&self.outgoing)? {
+            let bytes: Vec<u8> = self
+                .outgoing
+                .drain(..header.size + header.payload_size)
+                .collect();
+
+            // tungstenite doesn't split messages into fragments, but if it
+            // ever does, those just aren't compressed
+            if !(header.is_data() && header.fin && !header.rsv1) {
+                self.writable.extend(bytes);
+                continue;
+            }
+
+            let mut payload = bytes[header.size..].to_vec();
+            if let Some(mask) = header.mask {
+                apply_mask(&mut payload, mask);
+            }
+            let compressed = codec.compressor.compress(&payload);
+            self.writable
+                .extend(frame(0x80 | 0x40 | header.opcode, header.mask, &compressed));
+        }
+        Ok(())
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Compressed<S> {
+    /// Writes everything in `writable` to the socket.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        while !self.writable.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.writable))?;
+            if written == 0 {
+                return Poll::Ready(Err(IoErrorKind::WriteZero.into()));
+            }
+            self.writable.drain(..written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for Compressed<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<IoResult<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.readable.is_empty() {
+                let count = buf.remaining().min(this.readable.len());
+                buf.put_slice(&this.readable[..count]);
+                this.readable.drain(..count);
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut chunk = [0; 4096];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                // The end of the stream; whatever is left goes through as is
+                if this.incoming.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                this.readable = take(&mut this.incoming);
+                continue;
+            }
+
+            this.incoming.extend_from_slice(chunk.filled());
+            this.process_incoming()?;
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Compressed<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+        let this = self.get_mut();
+        if this.writable.len() >= WRITE_BUFFER_SIZE {
+            ready!(this.poll_drain(cx))?;
+        }
+
+        this.outgoing.extend_from_slice(buf);
+        this.process_outgoing()?;
+        // Get it moving, but there's no need to wait for it
+        if let Poll::Ready(Err(err)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `Hello`, and `Hello` again with context takeover (RFC 7692 section
+    /// 7.2.3)
+    const HELLO: [u8; 7] = [0xF2, 0x48, 0xCD, 0xC9, 0xC9, 0x07, 0x00];
+    const HELLO_AGAIN: [u8; 5] = [0xF2, 0x00, 0x11, 0x00, 0x00];
+
+    const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: example\r\n\r\n";
+
+    fn response(extensions: &str) -> Vec<u8> {
+        format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Extensions: {extensions}\r\n\r\n"
+        )
+        .into_bytes()
+    }
+
+    /// A stream that has seen the handshake response (and offered 11 bits).
+    fn connected(extensions: &str) -> Compressed<()> {
+        let mut stream = Compressed::with_offer((), Some(11));
+        stream.incoming = response(extensions);
+        stream.process_incoming().unwrap();
+        assert_eq!(stream.readable, response(extensions));
+        stream.readable.clear();
+        stream
+    }
+
+    /// Feeds bytes from the server through, returning what tungstenite
+    /// would read.
+    fn receive(stream: &mut Compressed<()>, bytes: &[u8]) -> IoResult<Vec<u8>> {
+        stream.incoming.extend_from_slice(bytes);
+        stream.process_incoming()?;
+        Ok(take(&mut stream.readable))
+    }
+
+    fn text(payload: &[u8]) -> Vec<u8> {
+        frame(0x81, None, payload)
+    }
+
+    #[test]
+    fn negotiates() {
+        let agreed = |extensions| negotiate(&response(extensions), 11);
+        assert!(agreed("permessage-deflate; server_max_window_bits=11")
+            .unwrap()
+            .is_some());
+        assert!(agreed(
+            "permessage-deflate; server_max_window_bits=10; \
+             server_no_context_takeover; client_no_context_takeover"
+        )
+        .unwrap()
+        .is_some());
+        assert!(negotiate(b"HTTP/1.1 101 Switching Protocols\r\n\r\n", 11)
+            .unwrap()
+            .is_none());
+
+        // A bigger window than offered fails the handshake
+        assert!(agreed("permessage-deflate; server_max_window_bits=12").is_err());
+        assert!(agreed("permessage-deflate; server_max_window_bits=16").is_err());
+        assert!(agreed("permessage-deflate; client_max_window_bits=9").is_err());
+        assert!(agreed("permessage-deflate; mystery").is_err());
+        assert!(agreed("x-webkit-deflate-frame").is_err());
+        assert!(agreed("permessage-deflate, permessage-deflate").is_err());
+    }
+
+    #[test]
+    fn inflates_with_context_takeover() {
+        let mut stream = connected("permessage-deflate; server_max_window_bits=11");
+        let mut frames = frame(0xC1, None, &HELLO);
+        frames.extend(frame(0xC1, None, &HELLO_AGAIN));
+        let mut expected = text(b"Hello");
+        expected.extend(text(b"Hello"));
+        assert_eq!(receive(&mut stream, &frames).unwrap(), expected);
+    }
+
+    #[test]
+    fn inflates_without_context_takeover() {
+        let mut stream = connected("permessage-deflate; server_no_context_takeover");
+        for _ in 0..2 {
+            let readable = receive(&mut stream, &frame(0xC1, None, &HELLO)).unwrap();
+            assert_eq!(readable, text(b"Hello"));
+        }
+        // That refers back to the last message, which is forgotten
+        let readable = receive(&mut stream, &frame(0xC1, None, &HELLO_AGAIN));
+        assert_ne!(readable.ok(), Some(text(b"Hello")));
+    }
+
+    #[test]
+    fn inflates_fragmented_messages() {
+        let mut stream = connected("permessage-deflate");
+        // RFC 7692 section 7.2.3.1, one byte at a time
+        let mut frames = vec![0x41, 0x03, 0xF2, 0x48, 0xCD];
+        frames.extend([0x80, 0x04, 0xC9, 0xC9, 0x07, 0x00]);
+        let readable: Vec<u8> = frames
+            .iter()
+            .flat_map(|&byte| receive(&mut stream, &[byte]).unwrap())
+            .collect();
+        assert_eq!(readable, text(b"Hello"));
+    }
+
+    #[test]
+    fn passes_control_frames_in_fragmented_messages() {
+        let mut stream = connected("permessage-deflate");
+        let ping = frame(0x89, None, b"ping");
+        let mut frames = vec![0x41, 0x03, 0xF2, 0x48, 0xCD];
+        frames.extend(&ping);
+        frames.extend([0x80, 0x04, 0xC9, 0xC9, 0x07, 0x00]);
+
+        let mut expected = ping.clone();
+        expected.extend(text(b"Hello"));
+        assert_eq!(receive(&mut stream, &frames).unwrap(), expected);
+    }
+
+    #[test]
+    fn passes_plain_frames() {
+        let mut stream = connected("permessage-deflate");
+        let mut frames = text(b"plain");
+        // Over the limit for inflated messages, which plain ones don't have
+        let big = frame(0x82, None, &vec![7; MAX_MESSAGE_SIZE + 1]);
+        frames.extend(&big);
+        // Fragmented and uncompressed
+        frames.extend(frame(0x01, None, b"a"));
+        frames.extend(frame(0x80, None, b"b"));
+        assert_eq!(receive(&mut stream, &frames).unwrap(), frames);
+    }
+
+    #[test]
+    fn limits_inflated_messages() {
+        let mut stream = connected("permessage-deflate; server_no_context_takeover");
+        let compressed = Compressor::new(false).compress(&vec![0; MAX_MESSAGE_SIZE]);
+        let readable = receive(&mut stream, &frame(0xC2, None, &compressed)).unwrap();
+        assert_eq!(readable, frame(0x82, None, &vec![0; MAX_MESSAGE_SIZE]));
+
+        // Only a few KiB on the wire, split up, and still too big
+        let compressed = Compressor::new(false).compress(&vec![0; MAX_MESSAGE_SIZE + 1]);
+        let (first, rest) = compressed.split_at(compressed.len() / 2);
+        let mut frames = frame(0x42, None, first);
+        frames.extend(frame(0x80, None, rest));
+        assert!(receive(&mut stream, &frames).is_err());
+    }
+
+    #[test]
+    fn rejects_masked_frames() {
+        let mut stream = connected("permessage-deflate");
+        let masked = frame(0xC1, Some([1, 2, 3, 4]), &HELLO);
+        assert!(receive(&mut stream, &masked).is_err());
+
+        let mut stream = connected("permessage-deflate");
+        assert!(receive(&mut stream, &frame(0x81, Some([1, 2, 3, 4]), b"plain")).is_err());
+    }
+
+    #[test]
+    fn rejects_misplaced_reserved_bits() {
+        // RSV1 on a continuation frame
+        let mut stream = connected("permessage-deflate");
+        let mut frames = vec![0x41, 0x03, 0xF2, 0x48, 0xCD];
+        frames.extend([0xC0, 0x04, 0xC9, 0xC9, 0x07, 0x00]);
+        assert!(receive(&mut stream, &frames).is_err());
+
+        // RSV1 on a control frame, in the middle of a message or not
+        let mut stream = connected("permessage-deflate");
+        let mut frames = vec![0x41, 0x03, 0xF2, 0x48, 0xCD];
+        frames.extend(frame(0xC9, None, b"ping"));
+        assert!(receive(&mut stream, &frames).is_err());
+        let mut stream = connected("permessage-deflate");
+        assert!(receive(&mut stream, &frame(0xC9, None, b"ping")).is_err());
+
+        // RSV2
+        let mut stream = connected("permessage-deflate");
+        assert!(receive(&mut stream, &frame(0xA1, None, b"plain")).is_err());
+    }
+
+    #[test]
+    fn deflates_outgoing_messages() {
+        let mask = [1, 2, 3, 4];
+        let mut stream = connected("permessage-deflate");
+        let ping = frame(0x89, Some(mask), b"ping");
+        stream.outgoing.extend(REQUEST);
+        stream.outgoing.extend(frame(0x81, Some(mask), b"Hello"));
+        stream.outgoing.extend(&ping);
+        stream.outgoing.extend(frame(0x82, Some(mask), b"Hello"));
+        stream.process_outgoing().unwrap();
+
+        let mut writable = &stream.writable[..];
+        assert!(writable.starts_with(REQUEST));
+        writable = &writable[REQUEST.len()..];
+
+        // What the server would make of it, context takeover and all
+        let mut decompressor = Decompressor::new(true);
+        let mut frames = Vec::new();
+        while let Some(header) = FrameHeader::parse(writable).unwrap() {
+            let mut payload = writable[header.size..header.size + header.payload_size].to_vec();
+            assert_eq!(header.mask, Some(mask));
+            apply_mask(&mut payload, mask);
+            if header.rsv1 {
+                payload = decompressor.decompress(&payload, 100).unwrap();
+            }
+            frames.push((header.fin, header.rsv1, header.opcode, payload));
+            writable = &writable[header.size + header.payload_size..];
+        }
+        assert!(writable.is_empty());
+        assert!(
+            frames
+                == [
+                    (true, true, 0x1, b"Hello".to_vec()),
+                    (true, false, 0x9, b"ping".to_vec()),
+                    (true, true, 0x2, b"Hello".to_vec()),
+                ]
+        );
+    }
+
+    #[test]
+    fn leaves_everything_alone_if_declined() {
+        let mut stream = Compressed::with_offer((), Some(11));
+        let response = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n";
+        let mut incoming = response.to_vec();
+        incoming.extend(text(b"plain"));
+        assert_eq!(receive(&mut stream, &incoming).unwrap(), incoming);
+
+        let mut outgoing = REQUEST.to_vec();
+        outgoing.extend(frame(0x81, Some([1, 2, 3, 4]), b"Hello"));
+        stream.outgoing.extend(&outgoing);
+        stream.process_outgoing().unwrap();
+        assert_eq!(stream.writable, outgoing);
+    }
+}
@@ -0,0 +1,179 @@
+// This file is part of cloudbit-software.
+//
+// cloudbit-software - an alternative software for the littleBits cloudBit.
+//
+// Copyright (C) 2024 littleBitsman
+//
+// cloudbit-software is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// cloudbit-software is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see https://www.gnu.org/licenses/.
+
+//! Optional settings file
+//!
+//! Read once at startup from [`CONFIG_PATH`]. Every key is optional, and a
+//! missing or broken file just means the defaults are used.
+
+use crate::{filter, hardware::Backend, input::Format};
+use serde::Deserialize;
+use serde_json::from_str;
+use std::{fs::read_to_string, io::ErrorKind as IoErrorKind, sync::OnceLock};
+
+pub const CONFIG_PATH: &str = "/usr/local/lb/cloud_client/config.json";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// How many failed attempts in a row before moving on to the next server.
+    pub failover_after: u32,
+    /// Reconnect delay after the first failure (before jitter).
+    pub backoff_min_ms: u64,
+    /// The reconnect delay never grows past this (before jitter).
+    pub backoff_max_ms: u64,
+    /// While connected to a fallback server, how often to check whether
+    /// the primary one is back.
+    pub primary_retry_secs: u64,
+    /// How often to ping the server (0 turns heartbeats off).
+    pub heartbeat_interval_secs: u64,
+    /// How long to wait for a pong before the connection is considered dead.
+    pub heartbeat_timeout_secs: u64,
+    /// Where INPUT events are kept while offline.
+    pub backlog_path: String,
+    /// The most INPUT events kept while offline (the oldest are dropped).
+    pub backlog_max_events: usize,
+    /// How often the offline buffer is written to disk while it has unsaved changes.
+    pub backlog_flush_secs: u64,
+    /// How many recent INPUT packets are kept for the server to ask for again.
+    pub resend_history: usize,
+    /// How long to wait for HELLO after IDENTIFY before assuming the server
+    /// doesn't know about it.
+    pub hello_timeout_ms: u64,
+    /// Port to accept WebSocket clients on directly (`None` = don't).
+    pub listen_port: Option<u16>,
+    /// Whether to connect to a server at all (turning this off only makes
+    /// sense with [`Config::listen_port`]).
+    pub upstream: bool,
+    /// Whether to advertise the cloudBit over mDNS.
+    pub mdns: bool,
+    /// How long to wait for mDNS answers (looking up `.local` names and
+    /// looking for a gateway).
+    pub mdns_timeout_ms: u64,
+    /// Port for the local HTTP API (`None` = off).
+    pub http_port: Option<u16>,
+    /// Token the HTTP API wants in `Authorization: Bearer` (`None` = no auth).
+    pub http_token: Option<String>,
+    /// Proxy for the connection to the server (`None` = from the
+    /// environment, if set there).
+    pub proxy: Option<String>,
+    /// PEM file with the CA certificates to trust instead of the bundled
+    /// ones.
+    pub tls_ca_file: Option<String>,
+    /// Base64 SHA-256 hashes of public keys (SPKI), one of which has to be
+    /// in the server's certificate chain (empty = no pinning).
+    pub tls_pins: Vec<String>,
+    /// This device's certificate (PEM), used if it exists.
+    pub tls_client_cert: String,
+    /// The key for [`Config::tls_client_cert`] (PEM).
+    pub tls_client_key: String,
+    /// The device secret for answering CHALLENGE (used if it exists).
+    pub auth_secret_path: String,
+    /// Whether servers that don't authenticate are still accepted. Turn
+    /// this off once every server does challenge-response.
+    pub auth_legacy: bool,
+    /// Commands the server may send (`None` = all of them).
+    pub allowed_opcodes: Option<Vec<u64>>,
+    /// Commands that have to be signed with the device secret (CONFIG by
+    /// default, since it can point the cloudBit at another server).
+    pub signed_opcodes: Vec<u64>,
+    /// How long the IO loop sleeps between ADC reads (the server can change
+    /// this, see [`crate::runtime`]).
+    pub loop_delay_ms: u64,
+    /// How much the input has to change to be sent, in raw 12-bit counts
+    /// (filters out ADC noise; the server can change this too).
+    pub input_delta_threshold: u16,
+    /// What INPUT reports the input as.
+    pub input_format: Format,
+    /// How the input is filtered before looking for changes (the server
+    /// can change this too).
+    pub input_filter: filter::Settings,
+    /// Whether to offer permessage-deflate to WebSocket servers.
+    pub deflate: bool,
+    /// Window size the server may use for permessage-deflate, as a power of two (9 to 15).
+    pub deflate_window_bits: u8,
+    /// What to use as the hardware (the real thing, or a mock for running
+    /// on a PC).
+    pub hardware: Backend,
+    /// Where the input and output calibration is kept.
+    pub calibration_path: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            failover_after: 3,
+            backoff_min_ms: 2000,
+            backoff_max_ms: 120_000,
+            primary_retry_secs: 300,
+            heartbeat_interval_secs: 15,
+            heartbeat_timeout_secs: 10,
+            backlog_path: String::from("/usr/local/lb/cloud_client/backlog"),
+            backlog_max_events: 4096,
+            backlog_flush_secs: 60,
+            resend_history: 256,
+            hello_timeout_ms: 2000,
+            listen_port: None,
+            upstream: true,
+            mdns: true,
+            mdns_timeout_ms: 3000,
+            http_port: None,
+            http_token: None,
+            proxy: None,
+            tls_ca_file: None,
+            tls_pins: Vec::new(),
+            tls_client_cert: String::from("/usr/local/lb/cloud_client/client.crt"),
+            tls_client_key: String::from("/usr/local/lb/cloud_client/client.key"),
+            auth_secret_path: String::from("/usr/local/lb/cloud_client/secret"),
+            auth_legacy: true,
+            allowed_opcodes: None,
+            signed_opcodes: vec![0xF5],
+            loop_delay_ms: 10,
+            // 2 steps of the old 8-bit reads, in 12-bit counts
+            input_delta_threshold: 32,
+            input_format: Format::Scaled,
+            input_filter: filter::Settings::NONE,
+            deflate: false,
+            deflate_window_bits: 11,
+            hardware: Backend::Mmio,
+            calibration_path: String::from("/usr/local/lb/cloud_client/calibration.json"),
+        }
+    }
+}
+
+/// Reads [`CONFIG_PATH`]. Does nothing if it was already loaded.
+pub fn load() {
+    CONFIG.get_or_init(|| match read_to_string(CONFIG_PATH) {
+        Ok(text) => from_str(&text).unwrap_or_else(|err| {
+            eprintln!("Error while parsing {CONFIG_PATH}: {err}; using defaults");
+            Config::default()
+        }),
+        Err(err) => {
+            if err.kind() != IoErrorKind::NotFound {
+                eprintln!("Error while reading {CONFIG_PATH}: {err}; using defaults");
+            }
+            Config::default()
+        }
+    });
+}
+
+/// Gets the loaded config (the defaults if [`load`] hasn't been called).
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
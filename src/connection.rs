@@ -0,0 +1,542 @@
+// This file is part of cloudbit-software.
+//
+// cloudbit-software - an alternative software for the littleBits cloudBit.
+//
+// Copyright (C) 2024 littleBitsman
+//
+// cloudbit-software is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// cloudbit-software is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see https://www.gnu.org/licenses/.
+
+//! Connection supervisor
+//!
+//! Owns the connection to the server and nothing else. When the connection
+//! drops only the socket is torn down; hardware, the LED thread and the
+//! outgoing message queue live in `main` and survive reconnects.
+//!
+//! The transports themselves live in their own modules. Everything they
+//! have in common (failover, heartbeats, the offline backlog, sequence
+//! numbers and the opcode handlers) is shared from here.
+
+#[cfg(feature = "mqtt")]
+use crate::mqtt::{self, MqttLink};
+#[cfg(feature = "udp")]
+use crate::udp::{self, UdpLink};
+use crate::{
+    auth, backlog, clock,
+    config::{self, Config},
+    handler::{self, LinkStats},
+    hardware::{self, Initialized},
+    input::{self, Format},
+    policy,
+    protocol::{self, Features},
+    runtime,
+    sequence::Sequencer,
+    servers::Servers,
+    stream,
+    websocket::{self, WebSocketLink},
+    LEDCommand,
+};
+use futures::{
+    channel::mpsc::{Receiver, Sender},
+    future::pending,
+};
+#[cfg(feature = "mqtt")]
+use rumqttc::ConnectionError;
+use serde_json::{json as serde_json, Value as JsonValue};
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    io::Error as IoError,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering::SeqCst},
+    time::Duration,
+};
+use tokio::{
+    spawn,
+    task::JoinHandle,
+    time::{interval_at, sleep, Instant, Interval, Sleep},
+};
+use tokio_tungstenite::tungstenite::error::ProtocolError;
+use url::Url;
+
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether there is a connection to a server that has been identified to
+/// (and has been sent the offline backlog).
+pub fn is_connected() -> bool {
+    CONNECTED.load(SeqCst)
+}
+
+/// Why a session with the server ended.
+pub enum Disconnect {
+    /// The server closed the connection (with the close frame, if any).
+    Closed(Option<String>),
+    /// The underlying socket failed.
+    Io(IoError),
+    /// The WebSocket protocol failed in a way the stream can't recover from.
+    Protocol(ProtocolError),
+    /// The MQTT connection failed.
+    #[cfg(feature = "mqtt")]
+    Mqtt(Box<ConnectionError>),
+    /// Challenge-response authentication failed (see [`auth`]).
+    Unauthenticated(String),
+    /// No pong came back in time, so the connection is probably half-open.
+    HeartbeatTimeout,
+    /// The primary server is reachable again, so this (fallback) connection
+    /// is dropped in favour of it.
+    FailBack,
+    /// The server list was changed with CONFIG (0xF5).
+    Reconfigured,
+}
+
+impl Display for Disconnect {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Closed(Some(frame)) => write!(f, "connection closed: {frame}"),
+            Self::Closed(None) => f.write_str("connection closed, no close frame was available"),
+            Self::Io(err) => write!(f, "connection lost: {err}"),
+            Self::Protocol(err) => write!(f, "WebSocket protocol error: {err}"),
+            #[cfg(feature = "mqtt")]
+            Self::Mqtt(err) => write!(f, "MQTT connection lost: {err}"),
+            Self::Unauthenticated(reason) => write!(f, "authentication failed: {reason}"),
+            Self::HeartbeatTimeout => f.write_str("no pong from server in time"),
+            Self::FailBack => f.write_str("primary server is back"),
+            Self::Reconfigured => f.write_str("server list changed"),
+        }
+    }
+}
+
+/// Everything the supervisor needs to identify itself to a server.
+#[derive(Clone)]
+pub struct Identity {
+    pub mac_address: String,
+    pub cb_id: String,
+    pub hardware: Initialized,
+}
+
+impl Identity {
+    /// The IDENTIFY (0x3) packet.
+    pub fn identify(&self) -> JsonValue {
+        let mut packet = protocol::identify(&self.mac_address, &self.cb_id, self.hardware);
+        packet["opcodes"] = policy::opcodes().into();
+        packet["auth"] = auth::algorithms().into();
+        packet["input"] = input::describe();
+        packet
+    }
+}
+
+/// Something packets can be sent through.
+pub trait PacketSink {
+    /// Most backlog events to put in one packet
+    const BACKLOG_BATCH_SIZE: usize;
+
+    /// Sends one packet. Errors the connection survives are logged and
+    /// swallowed; `Err` means the connection is gone.
+    async fn send_packet(&mut self, packet: &JsonValue) -> Result<(), Disconnect>;
+
+    /// Sends packets in order, stopping at the first error.
+    async fn send_all(&mut self, packets: Vec<JsonValue>) -> Result<(), Disconnect> {
+        for packet in packets {
+            self.send_packet(&packet).await?;
+        }
+        Ok(())
+    }
+}
+
+/// A connection that has been identified to the server (and has heard back
+/// from it, or given up waiting for HELLO).
+enum Link {
+    WebSocket(Box<WebSocketLink>),
+    #[cfg(feature = "udp")]
+    Udp(UdpLink),
+    #[cfg(feature = "mqtt")]
+    Mqtt(MqttLink),
+}
+
+/// The states the supervisor moves through.
+enum State {
+    Connecting,
+    Connected(Link),
+    Disconnected(Disconnect),
+}
+
+/// Everything a transport's session loop needs.
+pub struct Session<'a> {
+    pub link: LinkStats,
+    pub sequencer: &'a mut Sequencer,
+    pub outgoing: &'a mut Receiver<JsonValue>,
+    pub sender: &'a Sender<JsonValue>,
+    pub heartbeat: Heartbeat,
+    pub fail_back: FailBack,
+    pub clock: clock::Reference,
+    /// The nonce sent in AUTH, which signed commands have to carry
+    pub nonce: Option<String>,
+    /// The input format HELLO picked, if any
+    pub input_format: Option<Format>,
+}
+
+impl Session<'_> {
+    /// Gets a packet from [`Self::outgoing`] ready to send.
+    pub fn stamp(&mut self, packet: JsonValue) -> JsonValue {
+        let packet = input::convert_packet(packet, self.input_format);
+        self.sequencer.stamp(self.clock.add(packet))
+    }
+
+    /// Everything that happens once a connection is up and HELLO is sorted
+    /// out, before the transport's main loop: sending (or dropping) the
+    /// offline backlog and handling whatever the server sent first.
+    pub async fn start<S: PacketSink>(
+        &mut self,
+        sink: &mut S,
+        features: Features,
+        first_packet: Option<JsonValue>,
+    ) -> Result<(), Disconnect> {
+        self.sequencer.new_session(features.sequence);
+        self.input_format = features.input_format;
+
+        if features.backlog {
+            self.replay_backlog(sink).await?;
+        } else {
+            let dropped = backlog::take().len();
+            if dropped > 0 {
+                eprintln!(
+                    "server doesn't want the backlog, dropping {dropped} buffered input events"
+                );
+                backlog::flush().await;
+            }
+        }
+        CONNECTED.store(true, SeqCst);
+
+        // A server that doesn't know HELLO may have sent something else first
+        if let Some(packet) = first_packet {
+            self.handle(sink, packet).await?;
+        }
+
+        hardware::get().set_led(LEDCommand::Green);
+        hardware::get().set_led(LEDCommand::Hold);
+        Ok(())
+    }
+
+    /// Sends everything in the offline buffer as INPUT packets flagged with
+    /// `"backlog": true`. `data.value` is the newest value in the batch (so
+    /// servers that don't know about backlogs still end up with the right
+    /// value) and `data.events` has every buffered value with its timestamps.
+    /// The first one carries the [`clock::Reference`] like any first INPUT.
+    async fn replay_backlog<S: PacketSink>(&mut self, sink: &mut S) -> Result<(), Disconnect> {
+        let mut events = backlog::take();
+        if events.is_empty() {
+            return Ok(());
+        }
+        eprintln!("replaying {} buffered input events", events.len());
+
+        while !events.is_empty() {
+            let rest = events.split_off(events.len().min(S::BACKLOG_BATCH_SIZE));
+            let batch: Vec<JsonValue> = events
+                .iter()
+                .map(|event| {
+                    let mut entry = serde_json!({ "value": event.value });
+                    if let Some(mono_ms) = event.mono_ms {
+                        entry["mono_ms"] = mono_ms.into();
+                    }
+                    if let Some(timestamp) = event.timestamp {
+                        entry["timestamp"] = timestamp.into();
+                    }
+                    entry
+                })
+                .collect();
+
+            let packet = self.stamp(serde_json!({
+                "opcode": 0x1,
+                "backlog": true,
+                "data": {
+                    "value": events.last().unwrap().value,
+                    "events": batch
+                }
+            }));
+
+            if let Err(reason) = sink.send_packet(&packet).await {
+                events.extend(rest);
+                backlog::restore(events);
+                return Err(reason);
+            }
+            events = rest;
+        }
+
+        backlog::flush().await;
+        Ok(())
+    }
+
+    /// Handles a packet from the server and sends the replies. If the
+    /// server list was changed, the connection is dropped after that.
+    pub async fn handle<S: PacketSink>(
+        &mut self,
+        sink: &mut S,
+        packet: JsonValue,
+    ) -> Result<(), Disconnect> {
+        let replies = handler::handle_packet(
+            packet,
+            self.sender,
+            self.sequencer,
+            &self.link,
+            self.nonce.as_deref(),
+        );
+        sink.send_all(replies).await?;
+        if runtime::servers_changed() {
+            return Err(Disconnect::Reconfigured);
+        }
+        Ok(())
+    }
+}
+
+/// Client-side heartbeat: a ping every
+/// [`Config::heartbeat_interval_secs`], and the connection is considered
+/// dead if the pong doesn't come back within
+/// [`Config::heartbeat_timeout_secs`].
+pub struct Heartbeat {
+    enabled: bool,
+    interval: Interval,
+    timeout: Duration,
+    deadline: Pin<Box<Sleep>>,
+    count: u64,
+    /// The payload and send time of the ping that hasn't been answered yet
+    unanswered: Option<(u64, Instant)>,
+}
+
+impl Heartbeat {
+    pub fn new(config: &Config) -> Self {
+        let every = Duration::from_secs(config.heartbeat_interval_secs);
+        let timeout = Duration::from_secs(config.heartbeat_timeout_secs);
+        Self {
+            enabled: !every.is_zero(),
+            interval: interval_at(Instant::now() + every, every.max(Duration::from_secs(1))),
+            timeout,
+            deadline: Box::pin(sleep(timeout)),
+            count: 0,
+            unanswered: None,
+        }
+    }
+
+    /// Resolves with the payload for the next ping once it's time to send
+    /// one, or with `None` if the last one wasn't answered in time.
+    pub async fn next(&mut self) -> Option<u64> {
+        if self.unanswered.is_some() {
+            self.deadline.as_mut().await;
+            return None;
+        }
+        if !self.enabled {
+            return pending().await;
+        }
+
+        self.interval.tick().await;
+        self.count += 1;
+        let now = Instant::now();
+        self.unanswered = Some((self.count, now));
+        self.deadline.as_mut().reset(now + self.timeout);
+        Some(self.count)
+    }
+
+    /// Call when a pong comes back. Returns the round trip time if it
+    /// answers the last ping.
+    pub fn pong(&mut self, payload: u64) -> Option<Duration> {
+        match self.unanswered {
+            Some((count, sent_at)) if count == payload => {
+                self.unanswered = None;
+                Some(sent_at.elapsed())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// While connected to a fallback server, checks every
+/// [`Config::primary_retry_secs`] whether the primary one is back.
+pub struct FailBack {
+    primary: Option<Url>,
+    identity: Identity,
+    retry: Duration,
+    timer: Pin<Box<Sleep>>,
+    probing: Option<JoinHandle<bool>>,
+}
+
+impl FailBack {
+    fn new(primary: Option<Url>, identity: &Identity, config: &Config) -> Self {
+        let retry = Duration::from_secs(config.primary_retry_secs);
+        Self {
+            primary,
+            identity: identity.clone(),
+            retry,
+            timer: Box::pin(sleep(retry)),
+            probing: None,
+        }
+    }
+
+    /// Resolves once the primary server accepts connections again (never,
+    /// if this is the primary server).
+    pub async fn wait(&mut self) {
+        let Some(primary) = self.primary.clone() else {
+            return pending().await;
+        };
+
+        loop {
+            if self.probing.is_none() {
+                self.timer.as_mut().await;
+                self.probing = Some(spawn(probe(primary.clone(), self.identity.clone())));
+            }
+
+            let reachable = self.probing.as_mut().unwrap().await.unwrap_or(false);
+            self.probing = None;
+            if reachable {
+                return;
+            }
+            self.timer.as_mut().reset(Instant::now() + self.retry);
+        }
+    }
+}
+
+/// Checks whether a server accepts connections again, without staying
+/// connected to it.
+async fn probe(url: Url, identity: Identity) -> bool {
+    match url.scheme() {
+        #[cfg(feature = "udp")]
+        "udp" => udp::probe(&url).await,
+        #[cfg(feature = "mqtt")]
+        "mqtt" | "mqtts" => mqtt::probe(&url, &identity).await,
+        _ => websocket::probe(&url, &identity).await,
+    }
+}
+
+/// Connects and identifies to a server.
+async fn connect(url: &Url, identity: &Identity) -> Result<Link, String> {
+    match url.scheme() {
+        #[cfg(feature = "udp")]
+        "udp" => udp::connect(url, identity)
+            .await
+            .map(Link::Udp)
+            .map_err(|err| err.to_string()),
+        #[cfg(feature = "mqtt")]
+        "mqtt" | "mqtts" => mqtt::connect(url, identity).await.map(Link::Mqtt),
+        _ => websocket::connect(url, identity)
+            .await
+            .map(|v| Link::WebSocket(Box::new(v))),
+    }
+}
+
+/// Keeps a connection to one of `servers` up forever.
+///
+/// `outgoing` is drained into the connection while connected. `sender` is
+/// the other end of it and is handed to tasks that reply to the server
+/// later (like system stats).
+pub async fn supervise(
+    identity: Identity,
+    mut servers: Servers,
+    outgoing: &mut Receiver<JsonValue>,
+    sender: Sender<JsonValue>,
+) -> ! {
+    let config = config::get();
+    let mut sequencer = Sequencer::new(config.resend_history);
+
+    let mut state = State::Connecting;
+    loop {
+        state = match state {
+            State::Connecting => {
+                if runtime::take_servers_changed() {
+                    servers = Servers::load();
+                }
+                servers.discover().await;
+                let url = servers.active();
+                eprintln!(
+                    "Attempting to connect to {} ({})",
+                    url,
+                    url.host_str().unwrap_or("?")
+                );
+
+                hardware::get().set_led(LEDCommand::Teal);
+                hardware::get().set_led(LEDCommand::Blink);
+                match connect(url, &identity).await {
+                    Ok(link) => {
+                        servers.connected();
+                        State::Connected(link)
+                    }
+                    Err(err) => {
+                        eprintln!("failed to connect: {err}");
+                        servers.failed(config);
+                        hardware::get().set_led(LEDCommand::Red);
+                        hardware::get().set_led(LEDCommand::Blink);
+                        sleep(servers.delay(config)).await;
+                        State::Connecting
+                    }
+                }
+            }
+            State::Connected(link) => {
+                eprintln!("Successfully connected");
+                let session = Session {
+                    link: LinkStats {
+                        endpoint: servers.active().to_string(),
+                        failures: servers.total_failures(),
+                        latency: None,
+                    },
+                    sequencer: &mut sequencer,
+                    outgoing,
+                    sender: &sender,
+                    heartbeat: Heartbeat::new(config),
+                    fail_back: FailBack::new(
+                        servers.primary_if_inactive().cloned(),
+                        &identity,
+                        config,
+                    ),
+                    clock: clock::Reference::default(),
+                    nonce: None,
+                    input_format: None,
+                };
+                let reason = match link {
+                    Link::WebSocket(link) => websocket::run_session(*link, session).await,
+                    #[cfg(feature = "udp")]
+                    Link::Udp(link) => udp::run_session(link, session).await,
+                    #[cfg(feature = "mqtt")]
+                    Link::Mqtt(link) => mqtt::run_session(link, session).await,
+                };
+                CONNECTED.store(false, SeqCst);
+                stream::release(&sender);
+                State::Disconnected(reason)
+            }
+            State::Disconnected(Disconnect::FailBack) => {
+                eprintln!("primary server is back, switching to it");
+                servers.fail_back();
+                State::Connecting
+            }
+            State::Disconnected(Disconnect::Reconfigured) => {
+                eprintln!("server list changed, connecting to the new one");
+                State::Connecting
+            }
+            State::Disconnected(reason) => {
+                eprintln!("{reason}; reconnecting");
+                hardware::get().set_led(LEDCommand::Red);
+                hardware::get().set_led(LEDCommand::Blink);
+                sleep(servers.delay(config)).await;
+                State::Connecting
+            }
+        }
+    }
+}
+
+/// Splits the first packet from the server into HELLO or "not HELLO".
+///
+/// A server that sends some other packet first doesn't know about HELLO
+/// and gets [`Features::LEGACY`]. That other packet is returned so it can
+/// still be handled.
+pub fn hello_or_first(packet: Option<JsonValue>) -> (Features, Option<JsonValue>) {
+    match packet {
+        Some(packet) => match protocol::parse_hello(&packet) {
+            Some(features) => (features, None),
+            None => (Features::LEGACY, Some(packet)),
+        },
+        None => (Features::LEGACY, None),
+    }
+}
@@ -0,0 +1,279 @@
+// This file is part of cloudbit-software.
+//
+// cloudbit-software - an alternative software for the littleBits cloudBit.
+//
+// Copyright (C) 2024 littleBitsman
+//
+// cloudbit-software is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// cloudbit-software is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see https://www.gnu.org/licenses/.
+
+//! Raw DEFLATE (RFC 1951) for permessage-deflate, done by miniz_oxide
+//!
+//! Both sides keep a window of earlier messages for matches to refer back
+//! to ("context takeover"), unless that was turned off in the handshake.
+//! Every message ends with a sync flush, and the `00 00 FF FF` that ends
+//! one is left off on the wire (RFC 7692 section 7.2.1).
+
+use miniz_oxide::{
+    deflate::{core::CompressorOxide, stream::deflate},
+    inflate::stream::{inflate, InflateState},
+    DataFormat, MZError, MZFlush, MZStatus,
+};
+
+/// What a sync flush ends with
+const SYNC_TAIL: [u8; 4] = [0x00, 0x00, 0xFF, 0xFF];
+
+/// miniz's default compression level
+const LEVEL: u8 = 6;
+
+/// How much output is made at a time
+const CHUNK_SIZE: usize = 4096;
+
+pub struct Compressor {
+    takeover: bool,
+    /// Boxed, since it's a few hundred KiB
+    state: Box<CompressorOxide>,
+}
+
+impl Compressor {
+    pub fn new(takeover: bool) -> Self {
+        let mut state = Box::<CompressorOxide>::default();
+        state.set_format_and_level(DataFormat::Raw, LEVEL);
+        Self { takeover, state }
+    }
+
+    /// Compresses one message.
+    pub fn compress(&mut self, data: &[u8]) -> Vec<u8> {
+        if !self.takeover {
+            self.state.reset();
+        }
+
+        let mut out = Vec::new();
+        let mut input = data;
+        loop {
+            let start = out.len();
+            out.resize(start + CHUNK_SIZE, 0);
+            let result = deflate(&mut self.state, input, &mut out[start..], MZFlush::Sync);
+            out.truncate(start + result.bytes_written);
+            input = &input[result.bytes_consumed..];
+            match result.status {
+                // Only a full output buffer can leave more to flush
+                Ok(_) if input.is_empty() && result.bytes_written < CHUNK_SIZE => break,
+                Ok(_) => {}
+                Err(MZError::Buf) if input.is_empty() => break,
+                Err(err) => unreachable!("deflate failed: {err:?}"),
+            }
+        }
+
+        if out.ends_with(&SYNC_TAIL) {
+            out.truncate(out.len() - SYNC_TAIL.len());
+        }
+        out
+    }
+}
+
+pub struct Decompressor {
+    takeover: bool,
+    /// Boxed, since it has a 32 KiB window
+    state: Box<InflateState>,
+}
+
+impl Decompressor {
+    pub fn new(takeover: bool) -> Self {
+        Self {
+            takeover,
+            state: InflateState::new_boxed(DataFormat::Raw),
+        }
+    }
+
+    /// Inflates one message, failing if it's bigger than `limit`.
+    pub fn decompress(&mut self, data: &[u8], limit: usize) -> Result<Vec<u8>, &'static str> {
+        if !self.takeover {
+            self.state.reset(DataFormat::Raw);
+        }
+
+        let mut input = data.to_vec();
+        input.extend(SYNC_TAIL);
+        let mut input = &input[..];
+        let mut out = Vec::new();
+        loop {
+            let start = out.len();
+            out.resize(start + CHUNK_SIZE, 0);
+            let result = inflate(&mut self.state, input, &mut out[start..], MZFlush::None);
+            out.truncate(start + result.bytes_written);
+            input = &input[result.bytes_consumed..];
+            if out.len() > limit {
+                return Err("message too big");
+            }
+
+            match result.status {
+                // A final block: whatever follows starts over
+                Ok(MZStatus::StreamEnd) => {
+                    self.state.reset(DataFormat::Raw);
+                    return Ok(out);
+                }
+                Ok(_) if input.is_empty() && result.bytes_written < CHUNK_SIZE => return Ok(out),
+                Ok(_) => {}
+                // Nothing left to put out
+                Err(MZError::Buf) if input.is_empty() => return Ok(out),
+                Err(_) => return Err("bad compressed data"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `Hello`, and then `Hello` again with context takeover, from RFC 7692
+    /// section 7.2.3
+    const HELLO: [u8; 7] = [0xF2, 0x48, 0xCD, 0xC9, 0xC9, 0x07, 0x00];
+    const HELLO_AGAIN: [u8; 5] = [0xF2, 0x00, 0x11, 0x00, 0x00];
+
+    /// A backlog-sized INPUT packet, which zlib compresses with a dynamic
+    /// Huffman block.
+    fn backlog_packet(value: u16) -> Vec<u8> {
+        let events: Vec<String> = (0..12)
+            .map(|i| {
+                format!(
+                    r#"{{"value": {}, "timestamp": {}}}"#,
+                    i * 37,
+                    1_760_000_000_000_u64 + i * 250
+                )
+            })
+            .collect();
+        format!(
+            r#"{{"opcode": 1, "data": {{"value": {value}}}, "mono_ms": 4460220, "seq": 17, "backlog": false, "events": [{}]}}"#,
+            events.join(",")
+        )
+        .into_bytes()
+    }
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// Python's `zlib.compressobj(9, DEFLATED, -15)` output for
+    /// `backlog_packet(32768)` and then `backlog_packet(40000)`, with the
+    /// sync flush tails left off
+    const ZLIB_BACKLOG: [&str; 2] = [
+        "74914d0ec2201046afd2b0ee0286bfd6ab1863b045632ca506eca6e1ee820bb524c36af87833bcc046fc32f8d19243c3da868c269a5c6e6435d3ab841cb4ea523e717ef66717722484a2003467c13e4b9fcee5c50c8fc9dff2f66aa6607362573bc7c21f7fd34a53bc3b1ba271cba755d1ef4aeddfb51a2541ee482d5052ee6732c65054ef8732d16128ab4c592751b452050014ad5c41f6285ab942af3014ea57e51c452b57aed1bf82ca5550f4b3a0b89ed21b",
+        "aa2690c84c20d13f9ac8461319f9890c00",
+    ];
+
+    #[test]
+    fn compresses() {
+        let mut compressor = Compressor::new(true);
+        let mut decompressor = Decompressor::new(true);
+        let first = compressor.compress(b"Hello");
+        let second = compressor.compress(b"Hello");
+        // The second one only refers back to the first
+        assert!(second.len() < first.len());
+        assert_eq!(decompressor.decompress(&first, 100).unwrap(), b"Hello");
+        assert_eq!(decompressor.decompress(&second, 100).unwrap(), b"Hello");
+
+        let mut compressor = Compressor::new(false);
+        assert_eq!(compressor.compress(b"Hello"), compressor.compress(b"Hello"));
+        let empty = compressor.compress(b"");
+        assert!(Decompressor::new(false)
+            .decompress(&empty, 0)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn inflates_rfc_examples() {
+        let mut decompressor = Decompressor::new(true);
+        assert_eq!(decompressor.decompress(&HELLO, 100).unwrap(), b"Hello");
+        assert_eq!(
+            decompressor.decompress(&HELLO_AGAIN, 100).unwrap(),
+            b"Hello"
+        );
+
+        // A stored block (section 7.2.3.3), and an empty one
+        let stored = [
+            0x00, 0x05, 0x00, 0xFA, 0xFF, 0x48, 0x65, 0x6C, 0x6C, 0x6F, 0x00,
+        ];
+        let mut decompressor = Decompressor::new(false);
+        assert_eq!(decompressor.decompress(&stored, 100).unwrap(), b"Hello");
+        assert_eq!(decompressor.decompress(&HELLO, 100).unwrap(), b"Hello");
+        assert_eq!(decompressor.decompress(&[0x00], 100).unwrap(), b"");
+    }
+
+    #[test]
+    fn inflates_zlib_dynamic_blocks() {
+        let mut decompressor = Decompressor::new(true);
+        for (hex, value) in ZLIB_BACKLOG.iter().zip([32768, 40000]) {
+            let inflated = decompressor.decompress(&unhex(hex), 4096).unwrap();
+            assert_eq!(inflated, backlog_packet(value));
+        }
+    }
+
+    #[test]
+    fn inflates_final_blocks() {
+        // `Hello` with BFINAL set (section 7.2.3.4), and then another
+        let mut decompressor = Decompressor::new(false);
+        let last = [0xF3, 0x48, 0xCD, 0xC9, 0xC9, 0x07, 0x00, 0x00];
+        assert_eq!(decompressor.decompress(&last, 100).unwrap(), b"Hello");
+        assert_eq!(decompressor.decompress(&HELLO, 100).unwrap(), b"Hello");
+    }
+
+    #[test]
+    fn rejects_bad_data() {
+        let mut decompressor = Decompressor::new(true);
+        // Block type 3 doesn't exist
+        assert!(decompressor.decompress(&[0xFF, 0xFF], 100).is_err());
+    }
+
+    #[test]
+    fn stops_at_the_limit() {
+        let compressed = Compressor::new(true).compress(&[b'a'; 10_000]);
+        let mut decompressor = Decompressor::new(true);
+        assert!(decompressor.decompress(&compressed, 9_999).is_err());
+        let mut decompressor = Decompressor::new(true);
+        assert_eq!(
+            decompressor.decompress(&compressed, 10_000).unwrap(),
+            [b'a'; 10_000]
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        for takeover in [true, false] {
+            let mut compressor = Compressor::new(takeover);
+            let mut decompressor = Decompressor::new(takeover);
+            let mut total = 0;
+            for i in 0..500_u16 {
+                let message = backlog_packet(i.wrapping_mul(7919));
+                let compressed = compressor.compress(&message);
+                total += compressed.len();
+                assert_eq!(decompressor.decompress(&compressed, 4096).unwrap(), message);
+            }
+            assert!(
+                total < 500 * backlog_packet(0).len() / 2,
+                "barely compressed"
+            );
+        }
+
+        // Bigger than a chunk, both ways
+        let message: Vec<u8> = (0..100_000_u32)
+            .map(|i| (i.wrapping_mul(i) >> 7) as u8)
+            .collect();
+        let compressed = Compressor::new(true).compress(&message);
+        assert!(compressed.len() > CHUNK_SIZE);
+        let inflated = Decompressor::new(true).decompress(&compressed, 1 << 20);
+        assert_eq!(inflated.unwrap(), message);
+    }
+}
@@ -0,0 +1,440 @@
+// This file is part of cloudbit-software.
+//
+// cloudbit-software - an alternative software for the littleBits cloudBit.
+//
+// Copyright (C) 2024 littleBitsman
+//
+// cloudbit-software is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// cloudbit-software is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see https://www.gnu.org/licenses/.
+
+//! Noise filtering of the input
+//!
+//! Before the IO loop looks for changes (or streams the input), every
+//! sample goes through the filter set with `input_filter` (in the settings
+//! file, or with CONFIG (0xF5) at runtime):
+//!
+//! ```json
+//! {
+//!     "oversample": 4,
+//!     "stages": [
+//!         { "type": "median", "window": 5 },
+//!         { "type": "ema", "alpha": 0.2 },
+//!         { "type": "hysteresis", "band": 4 }
+//!     ]
+//! }
+//! ```
+//!
+//! `oversample` ADC reads are averaged into each sample, which then goes
+//! through the [`Stage`]s in order. Everything works on raw counts, before
+//! the input is converted to its [format](crate::input::Format). The
+//! default is no filtering at all.
+//!
+//! Like with [`stream`](crate::stream), changing the filter only swaps the
+//! settings here; the IO loop picks them up (and starts the stages over)
+//! before its next read.
+
+use crate::hardware;
+use serde::{Deserialize, Serialize};
+use serde_json::{from_value, Value as JsonValue};
+use std::{
+    collections::VecDeque,
+    ops::RangeInclusive,
+    sync::{
+        atomic::{AtomicU32, Ordering::SeqCst},
+        Mutex,
+    },
+};
+
+/// What `oversample` can be
+const OVERSAMPLE_RANGE: RangeInclusive<u16> = 1..=64;
+
+/// What the `window` of `average` and `median` can be
+const WINDOW_RANGE: RangeInclusive<usize> = 1..=64;
+
+/// The most stages there can be
+const MAX_STAGES: usize = 8;
+
+/// The settings the IO loop should be using
+static SETTINGS: Mutex<Settings> = Mutex::new(Settings::NONE);
+
+/// Bumped whenever [`SETTINGS`] changes, so the IO loop only has to look
+/// at an atomic on every read (32 bits, wrapping, like the one in
+/// [`stream`](crate::stream)).
+static GENERATION: AtomicU32 = AtomicU32::new(0);
+
+/// The last ADC read and what the filter made of it, as
+/// `raw << 16 | filtered`
+static LAST: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// ADC reads averaged into one sample (1 = one read per sample)
+    pub oversample: u16,
+    pub stages: Vec<Stage>,
+}
+
+#[derive(Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Stage {
+    /// The mean of the last `window` samples
+    Average { window: usize },
+    /// Exponential moving average: each sample moves the output `alpha`
+    /// (0-1) of the way towards it
+    Ema { alpha: f64 },
+    /// The median of the last `window` samples (gets rid of spikes)
+    Median { window: usize },
+    /// The output only moves once a sample is more than `band` counts away
+    /// from it, and then stops `band` short of the sample
+    Hysteresis { band: u16 },
+}
+
+impl Settings {
+    /// No filtering
+    pub const NONE: Self = Self {
+        oversample: 1,
+        stages: Vec::new(),
+    };
+
+    /// Checks that everything is in range. `Err` is what's wrong.
+    pub fn validate(&self) -> Result<(), String> {
+        if !OVERSAMPLE_RANGE.contains(&self.oversample) {
+            return Err(format!(
+                "input_filter oversample must be from {} to {}",
+                OVERSAMPLE_RANGE.start(),
+                OVERSAMPLE_RANGE.end()
+            ));
+        }
+        if self.stages.len() > MAX_STAGES {
+            return Err(format!("input_filter can have at most {MAX_STAGES} stages"));
+        }
+        for stage in &self.stages {
+            match *stage {
+                Stage::Average { window } | Stage::Median { window }
+                    if !WINDOW_RANGE.contains(&window) =>
+                {
+                    return Err(format!(
+                        "input_filter window must be from {} to {}",
+                        WINDOW_RANGE.start(),
+                        WINDOW_RANGE.end()
+                    ));
+                }
+                Stage::Ema { alpha } if !(alpha > 0.0 && alpha <= 1.0) => {
+                    return Err(String::from(
+                        "input_filter alpha must be above 0 and at most 1",
+                    ));
+                }
+                Stage::Hysteresis { band } if band > 4095 => {
+                    return Err(String::from("input_filter band must be from 0 to 4095"));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::NONE
+    }
+}
+
+/// Reads `input_filter` from a CONFIG packet.
+pub fn parse(value: &JsonValue) -> Result<Settings, String> {
+    let settings: Settings =
+        from_value(value.clone()).map_err(|err| format!("bad input_filter: {err}"))?;
+    settings.validate()?;
+    Ok(settings)
+}
+
+/// The filter in use.
+pub fn get() -> Settings {
+    SETTINGS.lock().unwrap().clone()
+}
+
+pub fn set(settings: Settings) {
+    *SETTINGS.lock().unwrap() = settings;
+    GENERATION.fetch_add(1, SeqCst);
+}
+
+/// The last ADC read and the filtered sample it ended up in (both raw
+/// counts), for debugging.
+pub fn last() -> (u16, u16) {
+    let last = LAST.load(SeqCst);
+    ((last >> 16) as u16, last as u16)
+}
+
+/// A stage and what it remembers
+enum State {
+    Average(usize, VecDeque<f64>),
+    Ema(f64, Option<f64>),
+    Median(usize, VecDeque<f64>),
+    Hysteresis(f64, Option<f64>),
+}
+
+impl State {
+    fn new(stage: Stage) -> Self {
+        match stage {
+            Stage::Average { window } => Self::Average(window, VecDeque::new()),
+            Stage::Ema { alpha } => Self::Ema(alpha, None),
+            Stage::Median { window } => Self::Median(window, VecDeque::new()),
+            Stage::Hysteresis { band } => Self::Hysteresis(f64::from(band), None),
+        }
+    }
+
+    fn apply(&mut self, sample: f64) -> f64 {
+        match self {
+            Self::Average(window, samples) => {
+                push(samples, *window, sample);
+                samples.iter().sum::<f64>() / samples.len() as f64
+            }
+            Self::Ema(alpha, output) => {
+                let output = output.get_or_insert(sample);
+                *output += *alpha * (sample - *output);
+                *output
+            }
+            Self::Median(window, samples) => {
+                push(samples, *window, sample);
+                let mut sorted: Vec<f64> = samples.iter().copied().collect();
+                sorted.sort_by(f64::total_cmp);
+                let middle = sorted.len() / 2;
+                if sorted.len().is_multiple_of(2) {
+                    (sorted[middle - 1] + sorted[middle]) / 2.0
+                } else {
+                    sorted[middle]
+                }
+            }
+            Self::Hysteresis(band, output) => {
+                let output = output.get_or_insert(sample);
+                if sample > *output + *band {
+                    *output = sample - *band;
+                } else if sample < *output - *band {
+                    *output = sample + *band;
+                }
+                *output
+            }
+        }
+    }
+}
+
+/// Adds a sample to a window, dropping the oldest if it's full.
+fn push(samples: &mut VecDeque<f64>, window: usize, sample: f64) {
+    if samples.len() >= window {
+        samples.pop_front();
+    }
+    samples.push_back(sample);
+}
+
+/// The IO loop's side of filtering: reads the input and runs it through
+/// the stages.
+pub struct Filter {
+    generation: u32,
+    oversample: u16,
+    stages: Vec<State>,
+}
+
+impl Filter {
+    pub fn new() -> Self {
+        let mut filter = Self {
+            generation: 0,
+            oversample: 1,
+            stages: Vec::new(),
+        };
+        filter.reload();
+        filter
+    }
+
+    fn reload(&mut self) {
+        let settings = SETTINGS.lock().unwrap();
+        self.generation = GENERATION.load(SeqCst);
+        self.oversample = settings.oversample;
+        self.stages = settings.stages.iter().copied().map(State::new).collect();
+    }
+
+    /// Reads the input, returning the filtered sample (a raw count).
+    pub fn read(&mut self) -> u16 {
+        if GENERATION.load(SeqCst) != self.generation {
+            self.reload();
+        }
+
+        let hardware = hardware::get();
+        let mut raw = 0;
+        let mut total = 0;
+        for _ in 0..self.oversample {
+            raw = hardware.read_input();
+            total += u32::from(raw);
+        }
+        let mut sample = f64::from(total) / f64::from(self.oversample);
+        for stage in &mut self.stages {
+            sample = stage.apply(sample);
+        }
+
+        let filtered = sample.round().clamp(0.0, f64::from(u16::MAX)) as u16;
+        LAST.store(u32::from(raw) << 16 | u32::from(filtered), SeqCst);
+        filtered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::mock::{self, MOCK};
+    use serde_json::json as serde_json;
+
+    /// What `stage` makes of `samples`, one output per sample.
+    fn run(stage: Stage, samples: &[f64]) -> Vec<f64> {
+        let mut state = State::new(stage);
+        samples.iter().map(|&v| state.apply(v)).collect()
+    }
+
+    fn with_stages(stages: &[Stage]) -> Settings {
+        Settings {
+            oversample: 1,
+            stages: stages.to_vec(),
+        }
+    }
+
+    #[test]
+    fn averages() {
+        let stage = Stage::Average { window: 3 };
+        assert_eq!(run(stage, &[3.0, 6.0, 9.0, 12.0]), [3.0, 4.5, 6.0, 9.0]);
+    }
+
+    #[test]
+    fn smooths_exponentially() {
+        let stage = Stage::Ema { alpha: 0.5 };
+        assert_eq!(
+            run(stage, &[10.0, 20.0, 20.0, 0.0]),
+            [10.0, 15.0, 17.5, 8.75]
+        );
+        let stage = Stage::Ema { alpha: 1.0 };
+        assert_eq!(run(stage, &[10.0, 20.0]), [10.0, 20.0]);
+    }
+
+    #[test]
+    fn takes_medians() {
+        // Not full yet with 2 samples, which is an even window
+        let stage = Stage::Median { window: 3 };
+        assert_eq!(run(stage, &[1.0, 100.0, 2.0, 3.0]), [1.0, 50.5, 2.0, 3.0]);
+
+        let stage = Stage::Median { window: 4 };
+        assert_eq!(
+            run(stage, &[4.0, 1.0, 3.0, 2.0, 9.0]),
+            [4.0, 2.5, 3.0, 2.5, 2.5]
+        );
+    }
+
+    #[test]
+    fn holds_within_the_band() {
+        let stage = Stage::Hysteresis { band: 4 };
+        let samples = [100.0, 104.0, 105.0, 97.0, 96.0, 100.0];
+        assert_eq!(
+            run(stage, &samples),
+            [100.0, 100.0, 101.0, 101.0, 100.0, 100.0]
+        );
+
+        // No band passes everything through
+        let stage = Stage::Hysteresis { band: 0 };
+        assert_eq!(run(stage, &[1.0, 2.0, 1.0]), [1.0, 2.0, 1.0]);
+    }
+
+    #[test]
+    fn validates_bounds() {
+        let oversample = |oversample| Settings {
+            oversample,
+            stages: Vec::new(),
+        };
+        assert!(oversample(0).validate().is_err());
+        assert!(oversample(1).validate().is_ok());
+        assert!(oversample(64).validate().is_ok());
+        assert!(oversample(65).validate().is_err());
+
+        for (stage, ok) in [
+            (Stage::Average { window: 0 }, false),
+            (Stage::Average { window: 64 }, true),
+            (Stage::Median { window: 1 }, true),
+            (Stage::Median { window: 65 }, false),
+            (Stage::Ema { alpha: 0.0 }, false),
+            (Stage::Ema { alpha: 1.0 }, true),
+            (Stage::Ema { alpha: 1.5 }, false),
+            (Stage::Ema { alpha: f64::NAN }, false),
+            (Stage::Hysteresis { band: 4095 }, true),
+            (Stage::Hysteresis { band: 4096 }, false),
+        ] {
+            assert_eq!(with_stages(&[stage]).validate().is_ok(), ok);
+        }
+
+        let stage = Stage::Hysteresis { band: 1 };
+        assert!(with_stages(&[stage; MAX_STAGES]).validate().is_ok());
+        assert!(with_stages(&[stage; MAX_STAGES + 1]).validate().is_err());
+    }
+
+    #[test]
+    fn parses() {
+        let parsed = parse(&serde_json!({
+            "oversample": 4,
+            "stages": [
+                { "type": "median", "window": 5 },
+                { "type": "ema", "alpha": 0.2 },
+                { "type": "hysteresis", "band": 4 }
+            ]
+        }))
+        .unwrap();
+        let expected = Settings {
+            oversample: 4,
+            stages: vec![
+                Stage::Median { window: 5 },
+                Stage::Ema { alpha: 0.2 },
+                Stage::Hysteresis { band: 4 },
+            ],
+        };
+        assert!(parsed == expected);
+
+        // Everything left out is no filtering
+        assert!(parse(&serde_json!({})).unwrap() == Settings::NONE);
+
+        for bad in [
+            serde_json!({ "oversample": 0 }),
+            serde_json!({ "stages": [{ "type": "mode", "window": 5 }] }),
+            serde_json!({ "stages": [{ "type": "median" }] }),
+            serde_json!({ "stages": [{ "type": "median", "window": 5, "extra": 1 }] }),
+            serde_json!({ "oversample": 2, "extra": true }),
+            serde_json!("median"),
+        ] {
+            assert!(parse(&bad).is_err());
+        }
+    }
+
+    #[test]
+    fn filters_reads() {
+        let _guard = mock::setup();
+        set(Settings {
+            oversample: 4,
+            stages: vec![Stage::Hysteresis { band: 10 }],
+        });
+        let mut filter = Filter::new();
+
+        MOCK.set_input(1000);
+        assert_eq!(filter.read(), 1000);
+        MOCK.set_input(1010);
+        assert_eq!(filter.read(), 1000);
+        MOCK.set_input(1011);
+        assert_eq!(filter.read(), 1001);
+        assert_eq!(last(), (1011, 1001));
+
+        // Picked up on the next read, starting the stages over
+        set(Settings::NONE);
+        MOCK.set_input(1005);
+        assert_eq!(filter.read(), 1005);
+        assert_eq!(last(), (1005, 1005));
+    }
+}
@@ -0,0 +1,219 @@
+// This file is part of cloudbit-software.
+//
+// cloudbit-software - an alternative software for the littleBits cloudBit.
+//
+// Copyright (C) 2024 littleBitsman
+//
+// cloudbit-software is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// cloudbit-software is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see https://www.gnu.org/licenses/.
+
+//! Opcode handlers
+//!
+//! Everything the server can ask the cloudBit to do is handled here, so every
+//! transport behaves the same way.
+
+use crate::{
+    calibrate, clock, filter,
+    hardware::{self, calibration},
+    policy, runtime,
+    sequence::{self, Sequencer},
+    stream, LEDCommand,
+};
+use futures::{channel::mpsc::Sender, SinkExt};
+use serde_json::{json as serde_json, to_string, Value as JsonValue};
+use std::{process::id as get_pid, time::Duration};
+use sysinfo::{ProcessesToUpdate, System};
+use tokio::{spawn, task::spawn_blocking, time::sleep};
+
+/// Connection details reported in system stats (0xF4).
+#[derive(Clone)]
+pub struct LinkStats {
+    pub endpoint: String,
+    pub failures: u64,
+    /// Round trip time of the last heartbeat
+    pub latency: Option<Duration>,
+}
+
+/// Handles a packet from the server, returning the replies (already
+/// stamped with their sequence numbers) to send back. `nonce` is the one
+/// sent in AUTH on this connection, if any (see [`policy`]).
+///
+/// `obj` is indexed as a [`JsonValue`] rather than a map on purpose: missing
+/// keys come out as `null` instead of panicking.
+pub fn handle_packet(
+    obj: JsonValue,
+    sender: &Sender<JsonValue>,
+    sequencer: &mut Sequencer,
+    link: &LinkStats,
+    nonce: Option<&str>,
+) -> Vec<JsonValue> {
+    if !obj.is_object() {
+        eprintln!("bad packet from server: {obj}");
+        return Vec::new();
+    }
+
+    if let Err(error) = policy::check(&obj, nonce) {
+        return vec![sequencer.stamp(error)];
+    }
+
+    let opcode = obj["opcode"].as_u64();
+    let mut replies = Vec::new();
+
+    let seq = obj["seq"].as_u64();
+    if let Some(seq) = seq.filter(|_| sequencer.is_enabled()) {
+        let is_new = sequencer.receive(seq);
+        if opcode == Some(0x2) {
+            replies.push(sequencer.stamp(sequence::ack(seq)));
+        }
+        if !is_new {
+            return replies;
+        }
+    }
+
+    match opcode {
+        Some(0x2) => {
+            // OUTPUT
+            if let Some(new) = obj["data"]["value"].as_u64() {
+                hardware::get().set_output(new as u16);
+            } else {
+                eprintln!("bad output packet: {}", to_string(&obj).unwrap())
+            }
+        }
+
+        // ACK (the server has every INPUT up to `ack`)
+        Some(0x4) if sequencer.is_enabled() => {
+            if let Some(seq) = obj["ack"].as_u64() {
+                sequencer.acked(seq);
+            }
+        }
+
+        // RESEND (the server missed INPUTs `from` to `to`, inclusive)
+        Some(0x5) if sequencer.is_enabled() => {
+            if let (Some(from), Some(to)) = (obj["from"].as_u64(), obj["to"].as_u64()) {
+                replies.extend(sequencer.resend(from, to));
+            } else {
+                eprintln!("bad resend packet: {}", json_str!(obj))
+            }
+        }
+
+        // STREAM (start, change or stop streaming the input)
+        Some(0xB) => {
+            if let Err(reason) = stream::handle(&obj, sender) {
+                eprintln!("bad stream packet: {reason}");
+                replies.push(sequencer.stamp(policy::error(0xB, &obj["seq"], reason)));
+            }
+        }
+
+        // Any numbers that match 0xFX where X is any digit is a developer
+        // opcode (LED set, button status, etc.)
+
+        // Set LED
+        Some(0xF0) => {
+            if let Some(command) = obj["led_command"].as_str() {
+                hardware::get().set_leds(LEDCommand::parse_chain(command));
+            } else {
+                eprintln!("bad set LED packet: {}", json_str!(obj))
+            }
+        }
+
+        // Get button (it is never sent normally)
+        Some(0xF1) => replies.push(sequencer.stamp(clock::stamp(serde_json!({
+            "opcode": 0xF2, // 0xF2 is button state (returned from 0xF1)
+            "data": {
+                "button": hardware::get().read_button()
+            }
+        })))),
+
+        // Get system stats (e.g., memory usage, CPU usage)
+        // Note: you should NOT be polling this
+        // More notes can be found in protocol details
+        Some(0xF3) => {
+            let mut sender = sender.clone();
+            let link = link.clone();
+            spawn(async move {
+                let mut stats = system_stats().await;
+                stats["endpoint"] = link.endpoint.into();
+                stats["connect_failures"] = link.failures.into();
+                stats["latency_ms"] = link.latency.map(|v| v.as_secs_f64() * 1000.0).into();
+
+                // Opcode 0xF4 is system stats (RETURNED from 0xF3)
+                // If the connection drops before this is sent, it goes out
+                // on the next one instead.
+                let _ = sender
+                    .send(clock::stamp(serde_json!({
+                        "opcode": 0xF4,
+                        "stats": stats
+                    })))
+                    .await;
+            });
+        }
+        // Read or change runtime settings (0xF6 is the reply)
+        Some(0xF5) => replies.push(sequencer.stamp(runtime::handle(&obj))),
+
+        // Run a calibration step (0xF8 is the reply). Steps take a while, so
+        // the reply is sent when it's done, like 0xF4.
+        Some(0xF7) => {
+            let mut sender = sender.clone();
+            let step = obj["step"].as_str().map(String::from);
+            spawn(async move {
+                let result = match step {
+                    Some(step) => spawn_blocking(move || calibrate::run(&step)).await.unwrap(),
+                    None => Ok(calibration::get()),
+                };
+                let _ = sender.send(clock::stamp(calibrate::reply(result))).await;
+            });
+        }
+
+        // HELLO and CHALLENGE only mean something right after IDENTIFY
+        Some(0x6) => eprintln!("unexpected HELLO from server, ignoring"),
+        Some(0x8) => eprintln!("unexpected CHALLENGE from server, ignoring"),
+        Some(opcode) => {
+            eprintln!("invalid opcode: {opcode}");
+            replies.push(sequencer.stamp(policy::error(opcode, &obj["seq"], "unsupported opcode")));
+        }
+        None => {}
+    }
+
+    replies
+}
+
+/// CPU and memory usage of this process, total memory and the CPU
+/// temperature. Takes [`sysinfo::MINIMUM_CPU_UPDATE_INTERVAL`] to measure
+/// the CPU usage.
+pub async fn system_stats() -> JsonValue {
+    let mut sysinfo = System::new_all();
+    let pid = (get_pid() as usize).into();
+    sysinfo.refresh_cpu_usage();
+    sysinfo.refresh_memory();
+    sysinfo.refresh_processes(ProcessesToUpdate::Some(&[pid]));
+
+    sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL).await;
+
+    sysinfo.refresh_cpu_usage();
+
+    let process = sysinfo.process(pid).unwrap();
+    let cpu = process.cpu_usage();
+    let mem_bytes = process.memory();
+    let total_mem = sysinfo.total_memory();
+    let mem_percent = ((mem_bytes as f64) / (total_mem as f64)) * 100.0;
+    let cpu_temp = hardware::get().read_temp() - 273.15;
+    let (raw, filtered) = filter::last();
+
+    serde_json!({
+        "cpu_usage": cpu,
+        "memory_usage": mem_bytes,
+        "total_memory": total_mem,
+        "memory_usage_percent": mem_percent,
+        "cpu_temp": cpu_temp,
+        "calibration": calibration::get(),
+        "input": { "raw": raw, "filtered": filtered }
+    })
+}
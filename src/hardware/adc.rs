@@ -17,18 +17,35 @@
 
 //! ADC wrapper
 
-use crate::hardware::mem::{map, peek, poke};
-use std::{io::Result as IoResult, sync::OnceLock};
+use crate::hardware::{
+    calibration,
+    mem::{map, peek, poke},
+};
+use std::{
+    io::Result as IoResult,
+    ptr::null_mut,
+    sync::{
+        atomic::{AtomicPtr, Ordering::SeqCst},
+        Mutex,
+    },
+};
 
 pub const ADC_PAGE: usize = 0x80050000;
 pub const ADC_SCHED_OFFSET: usize = 0x0004;
 pub const ADC_VALUE_OFFSET: usize = 0x0050;
 pub const ADC_CLEAR_OFFSET: usize = 0x0018;
 
-static mut ADC_POINTER: OnceLock<*mut u32> = OnceLock::new();
+/// The largest count the LRADC gives (it's 12 bits)
+pub const RAW_MAX: u16 = 0xFFF;
+
+static ADC_POINTER: AtomicPtr<u32> = AtomicPtr::new(null_mut());
+/// Held while a conversion is going on, so the IRQ bits of two readers (say,
+/// the main loop and the HTTP server) don't get mixed up
+static LOCK: Mutex<()> = Mutex::new(());
 
 fn get() -> Option<*mut u32> {
-    unsafe { ADC_POINTER.get().copied() }
+    let pointer = ADC_POINTER.load(SeqCst);
+    (!pointer.is_null()).then_some(pointer)
 }
 
 /// Initalizes ADC memory
@@ -54,14 +71,18 @@ pub fn init(fd: i32) -> IoResult<()> {
 
     let mmaped = map(fd, ADC_PAGE as i64)?;
     mem_init(mmaped);
-    unsafe { ADC_POINTER.set(mmaped).unwrap() }
+    ADC_POINTER.store(mmaped, SeqCst);
 
     Ok(())
 }
 
 /// Reads the ADC (also known as the *LR*ADC, or ***L***ow-***R***esolution **A**nalog to **D**igital **C**onverter)
-pub fn read() -> u16 {
+///
+/// This is the raw 12-bit count; see [`scale`] and friends for what it
+/// means.
+pub fn read_raw() -> u16 {
     if let Some(pointer) = get() {
+        let _lock = LOCK.lock().unwrap();
         poke(pointer, ADC_SCHED_OFFSET, 0x1);
 
         {
@@ -77,13 +98,9 @@ pub fn read() -> u16 {
             while (peek(pointer, 0x0010) & 0x1) == 0 {}
         }
 
-        let mut value = peek(pointer, ADC_VALUE_OFFSET) & 0xFFF;
+        let value = peek(pointer, ADC_VALUE_OFFSET) & 0xFFF;
         poke(pointer, ADC_CLEAR_OFFSET, 0x1); // clears the LRADC0_IRQ bit in HW_LRADC_CTRL1
-        value = ((value.clamp(200, 1700) - 200) * 0xFFFF) / 1500;
-
-        // That comment is still a lie
-        // I still have to clamp it lol
-        value.clamp(u8::MIN as u32, u8::MAX as u32) as u16
+        value as u16
     } else {
         println!("warning: no ADC page pointer found");
         0
@@ -93,6 +110,7 @@ pub fn read() -> u16 {
 /// Gets the CPU die temperature, in Kelvin.
 pub fn read_temp() -> f32 {
     if let Some(ptr) = get() {
+        let _lock = LOCK.lock().unwrap();
         // Channel 1 is converted from channel 8 (PMOS THIN)
         // Channel 2 is converted from channel 9 (NMOS THIN)
 
@@ -132,4 +150,25 @@ pub fn read_temp() -> f32 {
         println!("warning: no ADC page pointer found");
         f32::NAN
     }
-}
\ No newline at end of file
+}
+
+/// Stretches a raw count from the input's range (as [calibrated]) to the full
+/// 0-0xFFFF. Counts outside the range are clamped.
+///
+/// [calibrated]: calibration::InputCalibration
+pub fn scale(raw: u16) -> u16 {
+    let calibration::InputCalibration { min, max } = calibration::get().input;
+    let span = u32::from(max - min);
+    let value = u32::from(raw.clamp(min, max) - min);
+    (value * 0xFFFF / span) as u16
+}
+
+/// A raw count as a percentage of the input's range (0-100).
+pub fn percent(raw: u16) -> u16 {
+    ((u32::from(scale(raw)) * 100 + 0x7FFF) / 0xFFFF) as u16
+}
+
+/// A raw count as the voltage of the littleBits signal (0-5000 mV).
+pub fn millivolts(raw: u16) -> u16 {
+    ((u32::from(scale(raw)) * 5000 + 0x7FFF) / 0xFFFF) as u16
+}
@@ -0,0 +1,150 @@
+// This file is part of cloudbit-software.
+//
+// cloudbit-software - an alternative software for the littleBits cloudBit.
+//
+// Copyright (C) 2024 littleBitsman
+//
+// cloudbit-software is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// cloudbit-software is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see https://www.gnu.org/licenses/.
+
+//! Per-device calibration of the input and output
+//!
+//! No two cloudBits read or drive quite the same voltages. The calibration
+//! (measured by [`crate::calibrate`]) is kept on the SD card at
+//! [`Config::calibration_path`] and applied by [`adc::scale`] and
+//! [`dac::set`] (and everything built on them):
+//! - `input`: the raw ADC counts the input reads at 0 V (`min`) and at full
+//!   scale, 5 V (`max`)
+//! - `output`: the DAC sample for an output value is `value * gain +
+//!   offset`
+//!
+//! Until the cloudBit is calibrated, [`Calibration::DEFAULT`] is used.
+//!
+//! [`Config::calibration_path`]: crate::config::Config::calibration_path
+//! [`adc::scale`]: super::adc::scale
+//! [`dac::set`]: super::dac::set
+
+use crate::{config, runtime::write_atomically};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_str, to_string_pretty};
+use std::{
+    fs::{read_to_string, remove_file},
+    io::{ErrorKind as IoErrorKind, Result as IoResult},
+    sync::Mutex,
+};
+
+static CALIBRATION: Mutex<Calibration> = Mutex::new(Calibration::DEFAULT);
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+pub struct Calibration {
+    pub input: InputCalibration,
+    pub output: OutputCalibration,
+    /// When it was last changed (milliseconds since the Unix epoch; `None`
+    /// for the defaults)
+    pub calibrated_at: Option<u64>,
+}
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+pub struct InputCalibration {
+    /// Raw count at 0 V
+    pub min: u16,
+    /// Raw count at 5 V
+    pub max: u16,
+}
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+pub struct OutputCalibration {
+    pub gain: f64,
+    pub offset: f64,
+}
+
+impl Calibration {
+    /// The guesses used before calibration, which are about right for most
+    /// boards.
+    pub const DEFAULT: Self = Self {
+        input: InputCalibration {
+            min: 200,
+            max: 1700,
+        },
+        output: OutputCalibration {
+            gain: 1.0,
+            // Samples are signed, so the middle of the output range is 0
+            offset: -32768.0,
+        },
+        calibrated_at: None,
+    };
+
+    /// Whether using it would make sense (and not divide by zero).
+    pub fn is_valid(&self) -> bool {
+        self.input.min < self.input.max
+            && self.output.gain.is_finite()
+            && self.output.gain > 0.0
+            && self.output.offset.is_finite()
+    }
+}
+
+impl OutputCalibration {
+    /// The DAC sample for an output value.
+    pub fn sample(&self, value: u16) -> i16 {
+        (f64::from(value) * self.gain + self.offset)
+            .round()
+            .clamp(f64::from(i16::MIN), f64::from(i16::MAX)) as i16
+    }
+}
+
+/// The calibration in use.
+pub fn get() -> Calibration {
+    *CALIBRATION.lock().unwrap()
+}
+
+/// Reads [`Config::calibration_path`](crate::config::Config::calibration_path),
+/// if it's there.
+pub fn load() {
+    let path = &config::get().calibration_path;
+    let calibration = match read_to_string(path) {
+        Ok(text) => match from_str::<Calibration>(&text) {
+            Ok(calibration) if calibration.is_valid() => calibration,
+            Ok(_) => {
+                eprintln!("calibration in {path} makes no sense; not using it");
+                return;
+            }
+            Err(err) => {
+                eprintln!("Error while parsing {path}: {err}; not using it");
+                return;
+            }
+        },
+        Err(err) => {
+            if err.kind() != IoErrorKind::NotFound {
+                eprintln!("Error while reading {path}: {err}");
+            }
+            return;
+        }
+    };
+    *CALIBRATION.lock().unwrap() = calibration;
+}
+
+/// Saves a new calibration and starts using it.
+pub fn set(calibration: Calibration) -> IoResult<()> {
+    let path = &config::get().calibration_path;
+    write_atomically(path, to_string_pretty(&calibration).unwrap())?;
+    *CALIBRATION.lock().unwrap() = calibration;
+    Ok(())
+}
+
+/// Goes back to [`Calibration::DEFAULT`], deleting the saved calibration.
+pub fn reset() -> IoResult<()> {
+    match remove_file(&config::get().calibration_path) {
+        Err(err) if err.kind() != IoErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    *CALIBRATION.lock().unwrap() = Calibration::DEFAULT;
+    Ok(())
+}
@@ -17,13 +17,14 @@
 
 //! DAC wrapper
 
-use crate::hardware::mem::{map, peek, poke};
+use crate::hardware::{
+    calibration,
+    mem::{map, peek, poke},
+};
 use std::{
     io::Result as IoResult,
-    sync::{
-        atomic::{AtomicU32, Ordering::SeqCst},
-        OnceLock,
-    },
+    ptr::null_mut,
+    sync::atomic::{AtomicPtr, AtomicU32, Ordering::SeqCst},
 };
 
 pub const DAC_PAGE: usize = 0x80048000;
@@ -31,10 +32,11 @@ pub const DAC_STATE_OFFSET: usize = 0x40;
 pub const DAC_VALUE_OFFSET: usize = 0xF0;
 
 static LAST_DAC_READY_FLAG: AtomicU32 = AtomicU32::new(0);
-static mut DAC_POINTER: OnceLock<*mut u32> = OnceLock::new();
+static DAC_POINTER: AtomicPtr<u32> = AtomicPtr::new(null_mut());
 
 fn get() -> Option<*mut u32> {
-    unsafe { DAC_POINTER.get().copied() }
+    let pointer = DAC_POINTER.load(SeqCst);
+    (!pointer.is_null()).then_some(pointer)
 }
 
 /// Initalizes DAC memory
@@ -64,7 +66,7 @@ pub fn init(fd: i32) -> IoResult<()> {
 
     let mmaped = map(fd, DAC_PAGE as i64)?;
     mem_init(mmaped);
-    unsafe { DAC_POINTER.set(mmaped).unwrap() }
+    DAC_POINTER.store(mmaped, SeqCst);
 
     set_ready_flag(peek(mmaped, DAC_STATE_OFFSET) ^ 2);
 
@@ -78,10 +80,20 @@ fn set_ready_flag(v: u32) {
     LAST_DAC_READY_FLAG.store(v, SeqCst)
 }
 
-/// Set output
+/// Set output (mapped to a DAC sample by the [`calibration`])
 pub fn set(value: u16) {
+    set_sample(calibration::get().output.sample(value));
+}
+
+/// The raw DAC sample last written (by anyone, so it survives restarts)
+pub fn get_sample() -> Option<i16> {
+    get().map(|ptr| peek(ptr, DAC_VALUE_OFFSET) as u16 as i16)
+}
+
+/// Set output to a raw DAC sample
+pub fn set_sample(sample: i16) {
     if let Some(ptr) = get() {
-        let converted = (value ^ 0x8000) as u32;
+        let converted = sample as u16 as u32;
         let packed = (converted << 16) | converted;
 
         let mut curr_state;
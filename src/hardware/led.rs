@@ -17,14 +17,18 @@
 
 //! LED wrapper
 
+#[cfg(feature = "sim")]
+use crate::hardware::sim;
 use crate::{
     hardware::mem::{map, poke},
     LEDCommand,
 };
 use std::{
-    io::Result as IoResult,
+    io::{Error as IoError, Result as IoResult},
     process::Command,
+    ptr::null_mut,
     sync::{
+        atomic::{AtomicPtr, Ordering::SeqCst},
         mpsc::{channel, Sender, TryRecvError},
         OnceLock,
     },
@@ -36,10 +40,11 @@ const GPIO_PAGE: usize = 0x80018000;
 const SLEEP_DUR: Duration = Duration::from_millis(500);
 
 static LED_CMD_SENDER: OnceLock<Sender<LEDCommand>> = OnceLock::new();
-static mut GPIO_POINTER: OnceLock<*mut u32> = OnceLock::new();
+static GPIO_POINTER: AtomicPtr<u32> = AtomicPtr::new(null_mut());
 
 fn get() -> Option<*mut u32> {
-    unsafe { GPIO_POINTER.get().copied() }
+    let pointer = GPIO_POINTER.load(SeqCst);
+    (!pointer.is_null()).then_some(pointer)
 }
 
 fn mem_init(page: *mut u32) {
@@ -55,15 +60,25 @@ pub fn init(fd: i32) -> IoResult<()> {
     }
 
     // Telling LEDcolor.d that the LED should be "off" turns off the blink clock,
-    // if any was active.
-    Command::new("/usr/local/lb/LEDcolor/bin/setColor")
-        .arg("off")
-        .status()
-        .expect("Failed to disable LEDcolor.d");
+    // if any was active. There's no LEDcolor.d next to simulated registers.
+    #[cfg(feature = "sim")]
+    let simulated = sim::is_running();
+    #[cfg(not(feature = "sim"))]
+    let simulated = false;
+    if !simulated {
+        let status = Command::new("/usr/local/lb/LEDcolor/bin/setColor")
+            .arg("off")
+            .status()?;
+        if !status.success() {
+            return Err(IoError::other(format!(
+                "failed to disable LEDcolor.d: setColor {status}"
+            )));
+        }
+    }
 
     let mmaped = map(fd, GPIO_PAGE as i64)?;
     mem_init(mmaped);
-    unsafe { GPIO_POINTER.set(mmaped).unwrap() }
+    GPIO_POINTER.store(mmaped, SeqCst);
 
     let (send, recv) = channel();
     LED_CMD_SENDER.set(send).unwrap();
@@ -164,18 +179,3 @@ pub fn set(arg: LEDCommand) -> bool {
         false
     }
 }
-
-/// set led using a [`Vec<LEDCommand>`]
-///
-/// returns success as a boolean
-pub fn set_many(arg: Vec<LEDCommand>) -> bool {
-    if arg.is_empty() {
-        false
-    } else {
-        let mut combined = true;
-        for item in arg {
-            combined &= set(item);
-        }
-        combined
-    }
-}
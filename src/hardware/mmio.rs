@@ -0,0 +1,110 @@
+// This file is part of cloudbit-software.
+//
+// cloudbit-software - an alternative software for the littleBits cloudBit.
+//
+// Copyright (C) 2024 littleBitsman
+//
+// cloudbit-software is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// cloudbit-software is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see https://www.gnu.org/licenses/.
+
+//! The cloudBit's own hardware, through its memory-mapped registers
+
+#[cfg(feature = "sim")]
+use crate::hardware::sim;
+use crate::{
+    hardware::{adc, button, dac, led, Hardware, Initialized},
+    LEDCommand,
+};
+use std::{
+    fs::OpenOptions,
+    io::Error as IoError,
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+};
+
+/// The real hardware, through `/dev/mem` and the [`adc`], [`button`],
+/// [`dac`] and [`led`] wrappers.
+pub struct Mmio {
+    /// Whether the registers are `sim`'s instead of the real ones
+    #[cfg(feature = "sim")]
+    pub simulated: bool,
+}
+
+impl Hardware for Mmio {
+    fn init(&self) -> Initialized {
+        #[cfg(feature = "sim")]
+        if self.simulated {
+            sim::start();
+            // Nothing is mapped, so there's no file to map it from
+            return init_drivers(-1);
+        }
+
+        let devmem = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(2) // O_RDWR = 2
+            .open("/dev/mem")
+        {
+            Ok(v) => v,
+            Err(err) => {
+                eprintln!("failed to open /dev/mem: {err}");
+                return Initialized::default();
+            }
+        };
+
+        init_drivers(devmem.as_raw_fd())
+    }
+
+    fn read_input(&self) -> u16 {
+        adc::read_raw()
+    }
+
+    fn set_output(&self, value: u16) {
+        dac::set(value);
+    }
+
+    fn set_output_sample(&self, sample: i16) {
+        dac::set_sample(sample);
+    }
+
+    fn read_output_sample(&self) -> Option<i16> {
+        dac::get_sample()
+    }
+
+    fn read_button(&self) -> bool {
+        button::read()
+    }
+
+    fn set_led(&self, command: LEDCommand) -> bool {
+        led::set(command)
+    }
+
+    fn read_temp(&self) -> f32 {
+        adc::read_temp()
+    }
+}
+
+/// Initializes every driver, mapping their registers from `fd`.
+pub(super) fn init_drivers(fd: i32) -> Initialized {
+    let check = |origin: &str, result: Result<(), IoError>| match result {
+        Ok(()) => true,
+        Err(err) => {
+            eprintln!("failed to initialize {origin}: {err}");
+            false
+        }
+    };
+
+    Initialized {
+        adc: check("ADC", adc::init(fd)),
+        button: check("Button", button::init(fd)),
+        dac: check("DAC", dac::init(fd)),
+        led: check("LED", led::init(fd)),
+    }
+}
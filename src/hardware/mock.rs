@@ -0,0 +1,265 @@
+// This file is part of cloudbit-software.
+//
+// cloudbit-software - an alternative software for the littleBits cloudBit.
+//
+// Copyright (C) 2024 littleBitsman
+//
+// cloudbit-software is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// cloudbit-software is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see https://www.gnu.org/licenses/.
+
+//! Stand-in hardware for running the software on a PC
+//!
+//! Nothing is real: the input, button and temperature are whatever they
+//! were last set to (with [`Mock`]'s setters, or on stdin), and the output
+//! and LED are only logged and kept for checking. On stdin, one command per
+//! line:
+//! - `input <0-4095>` (a raw ADC count)
+//! - `button <on|off>`
+//! - `temp <degrees Celsius>`
+
+use crate::{
+    hardware::{
+        adc::RAW_MAX, calibration, parse_pressed, read_commands, Hardware, Initialized,
+        ZERO_CELSIUS,
+    },
+    LEDCommand,
+};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU16, AtomicU32, Ordering::SeqCst},
+    Mutex, PoisonError,
+};
+#[cfg(test)]
+use {
+    crate::hardware::{get, BACKEND},
+    std::{ptr::addr_eq, sync::MutexGuard},
+};
+
+pub static MOCK: Mock = Mock::new();
+
+/// In-memory hardware (see the module docs).
+pub struct Mock {
+    input: AtomicU16,
+    output: AtomicU16,
+    /// The DAC sample for `output`, or the last one set straight
+    sample: AtomicU16,
+    button: AtomicBool,
+    /// In Kelvin, as [`f32::to_bits`]
+    temp: AtomicU32,
+    /// The last chain of LED commands
+    leds: Mutex<Vec<LEDCommand>>,
+}
+
+impl Mock {
+    const fn new() -> Self {
+        Self {
+            input: AtomicU16::new(0),
+            output: AtomicU16::new(0),
+            sample: AtomicU16::new(0),
+            button: AtomicBool::new(false),
+            // 25 °C
+            temp: AtomicU32::new(0x4395_1333),
+            leds: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Sets the input (a raw ADC count, at most [`RAW_MAX`]).
+    pub fn set_input(&self, value: u16) {
+        self.input.store(value.min(RAW_MAX), SeqCst);
+    }
+
+    pub fn set_button(&self, pressed: bool) {
+        self.button.store(pressed, SeqCst);
+    }
+
+    pub fn set_temp(&self, celsius: f32) {
+        self.temp.store((celsius + ZERO_CELSIUS).to_bits(), SeqCst);
+    }
+
+    /// Carries out a command from stdin.
+    fn command(name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "input" => {
+                let value = value
+                    .parse()
+                    .ok()
+                    .filter(|&v| v <= RAW_MAX)
+                    .ok_or("expected an ADC count (0-4095)")?;
+                MOCK.set_input(value);
+            }
+            "button" => MOCK.set_button(parse_pressed(value)?),
+            "temp" => MOCK.set_temp(value.parse().map_err(|_| "bad temperature")?),
+            _ => return Err(format!("unknown command {name}")),
+        }
+        Ok(())
+    }
+}
+
+impl Hardware for Mock {
+    fn init(&self) -> Initialized {
+        read_commands(Self::command);
+
+        Initialized {
+            adc: true,
+            button: true,
+            dac: true,
+            led: true,
+        }
+    }
+
+    fn read_input(&self) -> u16 {
+        self.input.load(SeqCst)
+    }
+
+    fn set_output(&self, value: u16) {
+        let sample = calibration::get().output.sample(value);
+        self.sample.store(sample as u16, SeqCst);
+        if self.output.swap(value, SeqCst) != value {
+            eprintln!("mock hardware: output {value}");
+        }
+    }
+
+    fn set_output_sample(&self, sample: i16) {
+        self.sample.store(sample as u16, SeqCst);
+        eprintln!("mock hardware: output sample {sample}");
+    }
+
+    fn read_output_sample(&self) -> Option<i16> {
+        Some(self.sample.load(SeqCst) as i16)
+    }
+
+    fn read_button(&self) -> bool {
+        self.button.load(SeqCst)
+    }
+
+    fn set_led(&self, command: LEDCommand) -> bool {
+        self.set_leds(vec![command])
+    }
+
+    fn set_leds(&self, commands: Vec<LEDCommand>) -> bool {
+        if commands.is_empty() {
+            return false;
+        }
+        for command in &commands {
+            eprintln!("mock hardware: LED {command}");
+        }
+        *self.leds.lock().unwrap_or_else(PoisonError::into_inner) = commands;
+        true
+    }
+
+    fn read_temp(&self) -> f32 {
+        f32::from_bits(self.temp.load(SeqCst))
+    }
+}
+
+/// There's only one mock, so one test at a time.
+#[cfg(test)]
+static SHARED: Mutex<()> = Mutex::new(());
+
+/// Makes the mock the hardware in use, for a test that has it to itself
+/// while the guard is held.
+#[cfg(test)]
+pub fn setup() -> MutexGuard<'static, ()> {
+    let guard = SHARED.lock().unwrap_or_else(PoisonError::into_inner);
+    let _ = BACKEND.set(&MOCK);
+    assert!(addr_eq(get(), &MOCK));
+    guard
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        handler::{handle_packet, LinkStats},
+        sequence::Sequencer,
+    };
+    use futures::channel::mpsc::channel;
+    use serde_json::{json as serde_json, Value as JsonValue};
+
+    /// Runs `packets` through the handler like one connection would,
+    /// returning every reply.
+    fn handle(packets: &[JsonValue]) -> Vec<JsonValue> {
+        let (sender, _receiver) = channel(8);
+        let mut sequencer = Sequencer::new(16);
+        sequencer.new_session(true);
+        let link = LinkStats {
+            endpoint: String::from("ws://localhost/"),
+            failures: 0,
+            latency: None,
+        };
+        packets
+            .iter()
+            .flat_map(|packet| handle_packet(packet.clone(), &sender, &mut sequencer, &link, None))
+            .collect()
+    }
+
+    #[test]
+    fn sets_output() {
+        let _guard = setup();
+        let replies = handle(&[
+            serde_json!({ "opcode": 2, "data": { "value": 512 }, "seq": 1 }),
+            // A duplicate is acknowledged but not applied
+            serde_json!({ "opcode": 2, "data": { "value": 100 }, "seq": 1 }),
+        ]);
+        assert_eq!(MOCK.output.load(SeqCst), 512);
+        assert_eq!(replies.len(), 2);
+        for reply in replies {
+            assert_eq!(reply["opcode"], 0x4);
+            assert_eq!(reply["ack"], 1);
+        }
+
+        handle(&[serde_json!({ "opcode": 2, "data": { "value": 0 } })]);
+        assert_eq!(MOCK.output.load(SeqCst), 0);
+    }
+
+    #[test]
+    fn sets_led() {
+        let _guard = setup();
+        let replies = handle(&[serde_json!({ "opcode": 0xF0, "led_command": "red, blink" })]);
+        assert!(replies.is_empty());
+        assert!(*MOCK.leds.lock().unwrap() == [LEDCommand::Red, LEDCommand::Blink]);
+
+        // Nothing known in the chain leaves the LED alone
+        handle(&[serde_json!({ "opcode": 0xF0, "led_command": "plaid" })]);
+        assert!(*MOCK.leds.lock().unwrap() == [LEDCommand::Red, LEDCommand::Blink]);
+    }
+
+    #[test]
+    fn reads_button() {
+        let _guard = setup();
+        for pressed in [true, false] {
+            MOCK.set_button(pressed);
+            let replies = handle(&[serde_json!({ "opcode": 0xF1 })]);
+            assert_eq!(replies.len(), 1);
+            assert_eq!(replies[0]["opcode"], 0xF2);
+            assert_eq!(replies[0]["data"]["button"], pressed);
+        }
+    }
+
+    #[test]
+    fn reads_input_and_temperature() {
+        let _guard = setup();
+        MOCK.set_input(5000);
+        assert_eq!(get().read_input(), RAW_MAX);
+        MOCK.set_input(1024);
+        assert_eq!(get().read_input(), 1024);
+        MOCK.set_temp(40.0);
+        assert!((get().read_temp() - ZERO_CELSIUS - 40.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn refuses_unsigned_config() {
+        let _guard = setup();
+        let replies = handle(&[serde_json!({ "opcode": 0xF5, "config": { "loop_delay_ms": 50 } })]);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0]["opcode"], 0xA);
+        assert_eq!(replies[0]["rejected"], 0xF5);
+    }
+}
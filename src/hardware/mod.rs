@@ -16,41 +16,175 @@
 // along with this program. If not, see https://www.gnu.org/licenses/.
 
 //! Contains all hardware wrappers.
+//!
+//! The rest of the software only talks to the hardware through the
+//! [`Hardware`] trait, so it can run against the real thing ([`Mmio`]) or
+//! a stand-in for trying things out on a PC: [`Mock`](mock::Mock), or the
+//! same drivers as the real thing on top of simulated registers (`sim`,
+//! only in builds with the `sim` feature).
+//! Which one is used is [`Config::hardware`].
+//!
+//! [`Config::hardware`]: crate::config::Config::hardware
 
-use std::{
-    fs::OpenOptions,
-    io::Error as IoError,
-    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
-};
+use crate::{config, LEDCommand};
+use serde::Deserialize;
+use std::{io::stdin, sync::OnceLock, thread::spawn};
 
 pub mod adc;
 pub mod button;
+pub mod calibration;
 pub mod dac;
 pub mod led;
+mod mmio;
+pub mod mock;
+#[cfg(feature = "sim")]
+mod sim;
 
-pub fn init_all() -> Result<(), (&'static str, IoError)> {
-    let devmem = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .custom_flags(2) // O_RDWR = 2
-        .open("/dev/mem")
-        .map_err(|v| ("failed to open /dev/mem", v))?;
+use mmio::Mmio;
 
-    let fd = devmem.as_raw_fd();
+/// Everything the software needs from the hardware.
+pub trait Hardware: Sync {
+    /// Sets the hardware up. Anything that fails is logged and left out,
+    /// so the rest can still be used (and the server can be told what's
+    /// missing).
+    fn init(&self) -> Initialized;
 
-    adc::init(fd).map_err(|v| ("ADC", v))?;
-    button::init(fd).map_err(|v| ("Button", v))?;
-    dac::init(fd).map_err(|v| ("DAC", v))?;
-    led::init(fd).map_err(|v| ("LED", v))?;
+    /// Reads the input, as a raw 12-bit ADC count (see [`adc::scale`] and
+    /// friends).
+    fn read_input(&self) -> u16;
 
-    Ok(())
+    /// Sets the output.
+    fn set_output(&self, value: u16);
+
+    /// Sets the output to a raw (signed) DAC sample, skipping the
+    /// [`calibration`].
+    fn set_output_sample(&self, sample: i16);
+
+    /// The raw DAC sample the output is at (`None` if there's no DAC).
+    fn read_output_sample(&self) -> Option<i16>;
+
+    /// Whether the button is pressed.
+    fn read_button(&self) -> bool;
+
+    /// Sends a command to the LED. Returns whether it was taken.
+    fn set_led(&self, command: LEDCommand) -> bool;
+
+    /// The CPU die temperature, in Kelvin.
+    fn read_temp(&self) -> f32;
+
+    /// Sends a chain of commands to the LED. Returns whether all of them
+    /// were taken (`false` if there weren't any).
+    fn set_leds(&self, commands: Vec<LEDCommand>) -> bool {
+        if commands.is_empty() {
+            return false;
+        }
+        let mut combined = true;
+        for command in commands {
+            combined &= self.set_led(command);
+        }
+        combined
+    }
+}
+
+/// The [`Hardware`] implementations to pick from.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// The cloudBit's own hardware
+    #[default]
+    Mmio,
+    /// Nothing real (see [`mock`])
+    Mock,
+    /// The cloudBit's drivers, on simulated registers (see `sim`; needs
+    /// the `sim` feature)
+    Sim,
+}
+
+/// 0 °C, in Kelvin
+const ZERO_CELSIUS: f32 = 273.15;
+
+static BACKEND: OnceLock<&'static dyn Hardware> = OnceLock::new();
+
+/// Which hardware initialized successfully.
+#[derive(Clone, Copy, Default)]
+pub struct Initialized {
+    pub adc: bool,
+    pub button: bool,
+    pub dac: bool,
+    pub led: bool,
+}
+
+/// The hardware in use.
+pub fn get() -> &'static dyn Hardware {
+    *BACKEND.get_or_init(|| match config::get().hardware {
+        Backend::Mmio => &Mmio {
+            #[cfg(feature = "sim")]
+            simulated: false,
+        },
+        Backend::Mock => {
+            eprintln!("using mock hardware");
+            &mock::MOCK
+        }
+        #[cfg(feature = "sim")]
+        Backend::Sim => {
+            eprintln!("using simulated hardware");
+            &Mmio { simulated: true }
+        }
+        #[cfg(not(feature = "sim"))]
+        Backend::Sim => {
+            eprintln!(
+                "this build can't simulate hardware (it needs --features sim), using mock hardware"
+            );
+            &mock::MOCK
+        }
+    })
+}
+
+/// Initializes the hardware in use.
+pub fn init() -> Initialized {
+    get().init()
+}
+
+/// For hardware that isn't real: reads `<name> <value>` lines from stdin
+/// (on a thread of its own) and passes them to `command`.
+fn read_commands(command: fn(&str, &str) -> Result<(), String>) {
+    spawn(move || {
+        for line in stdin().lines() {
+            let Ok(line) = line else {
+                return;
+            };
+            let mut words = line.split_whitespace();
+            let result = match (words.next(), words.next(), words.next()) {
+                (None, _, _) => continue,
+                (Some(name), Some(value), None) => command(name, value),
+                _ => Err(String::from("expected `<name> <value>`")),
+            };
+            if let Err(err) = result {
+                eprintln!("{line:?}: {err}");
+            }
+        }
+    });
+}
+
+/// Parses a button state typed on stdin.
+fn parse_pressed(value: &str) -> Result<bool, String> {
+    match value {
+        "on" | "1" | "true" => Ok(true),
+        "off" | "0" | "false" => Ok(false),
+        _ => Err(String::from("expected `on` or `off`")),
+    }
 }
 
 /// Memory module containing:
 /// - [`peek`] (read memory at `page` offset by `offset`)
 /// - [`poke`] (write to memory at `page` offset by `offset`, setting it to `value`)
 /// - [`map`] (wrapper for [`libc::mmap`])
+///
+/// In builds with the `sim` feature, all three go to `sim`'s registers
+/// instead while it's running.
 mod mem {
+    #[cfg(feature = "sim")]
+    use super::sim;
     use libc::{mmap, MAP_FAILED, MAP_SHARED, PROT_READ, PROT_WRITE};
     use std::{
         io::{Error as IoError, Result as IoResult},
@@ -75,6 +209,10 @@ mod mem {
     /// - The caller must ensure that reading from this memory address does not cause any unintended side effects
     ///   or undefined behavior, particularly in the context of hardware interaction.
     pub fn peek(page: *mut u32, offset: usize) -> u32 {
+        #[cfg(feature = "sim")]
+        if let Some(value) = sim::peek(page, offset) {
+            return value;
+        }
         // SAFETY: The caller must guarantee that `page` is a valid, non-null pointer
         // pointing to a memory region that can be safely read from, and that `offset`
         // is within the bounds of that memory region. The operation will perform a
@@ -98,6 +236,10 @@ mod mem {
     /// - The caller must ensure that writing to this memory address does not cause any unintended side effects
     ///   or undefined behavior, particularly in the context of hardware interaction.
     pub fn poke(page: *mut u32, offset: usize, value: u32) {
+        #[cfg(feature = "sim")]
+        if sim::poke(page, offset, value) {
+            return;
+        }
         // SAFETY: The caller must guarantee that `page` is a valid, non-null pointer
         // pointing to a memory region that can be safely written to, and that `offset`
         // is within the bounds of that memory region. The operation will perform a
@@ -118,6 +260,10 @@ mod mem {
     /// The `offset` must be aligned and within the bounds of the file defined
     /// by the file descriptor.
     pub fn map<T>(fd: i32, offset: i64) -> IoResult<*mut T> {
+        #[cfg(feature = "sim")]
+        if let Some(page) = sim::map(offset) {
+            return page.map(<*mut u32>::cast);
+        }
         // SAFETY: FFI functions are marked unsafe since the compiler cannot verify
         // behavior, but mmap is OK (or should be).
         // This function assumes that `offset` is aligned and is valid.
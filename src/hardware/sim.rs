@@ -0,0 +1,536 @@
+// This file is part of cloudbit-software.
+//
+// cloudbit-software - an alternative software for the littleBits cloudBit.
+//
+// Copyright (C) 2024 littleBitsman
+//
+// cloudbit-software is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// cloudbit-software is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see https://www.gnu.org/licenses/.
+
+//! Simulated i.MX23 registers
+//!
+//! With `"hardware": "sim"`, the [`adc`], [`button`], [`dac`] and [`led`]
+//! drivers run just like on a cloudBit, except [`mem`] hands them the
+//! registers simulated here instead of mapping `/dev/mem`. Only what the
+//! drivers use is modeled:
+//! - every register has the usual SET, CLR and TOG addresses (+0x4, +0x8
+//!   and +0xC)
+//! - LRADC: scheduling channels in `HW_LRADC_CTRL0` converts them straight
+//!   away (unless the block is gated or in reset), flips TOGGLE in their
+//!   `HW_LRADC_CHn` and sets their IRQ bits in `HW_LRADC_CTRL1`, which
+//!   stay set until cleared. `HW_LRADC_CTRL4` picks the physical channel
+//!   for each virtual one, DIVIDE_BY_TWO halves the reading, and the
+//!   temperature sensor reads 0 while TEMPSENSE_PWD is set
+//! - PINCTRL: a GPIO pin only drives its `DOUT` bit when its `MUXSEL` is
+//!   GPIO and its `DOE` bit is set. The LED pins are active low, and the
+//!   button pulls its pin (bank 1, pin 7) low in `HW_PINCTRL_DIN1`
+//! - AUDIOOUT: while the DAC is running, the ready flag (bit 1 of 0x40)
+//!   flips every time it's read (as if the DAC took a sample), and the
+//!   output is whatever was last written to `HW_AUDIOOUT_DATA`
+//!
+//! Registers start out the way the cloudBit's Linux leaves them, which the
+//! drivers count on: the LRADC gated with the temperature sensor off and
+//! virtual channels 1 and 2 selecting channel 0 (the drivers only OR their
+//! selects in), the red LED pin's output already enabled (the drivers only
+//! enable green and blue), the button pin set up as GPIO and the DAC
+//! running. The LED and output are logged when they change,
+//! and the rest comes from stdin, one command per line:
+//! - `input <millivolts>` (on the input's ADC pin)
+//! - `button <on|off>`
+//! - `temp <degrees Celsius>`
+//! - `loopback <on|off>`: with it on, the output is wired back into the
+//!   input (through a slightly off output bit and the input's divider, so
+//!   there's something for [calibration](crate::calibrate) to correct)
+//!
+//! [`adc`]: super::adc
+//! [`button`]: super::button
+//! [`dac`]: super::dac
+//! [`led`]: super::led
+//! [`mem`]: super::mem
+
+use crate::hardware::{mem::MAP_SIZE, parse_pressed, read_commands, ZERO_CELSIUS};
+use std::{
+    io::{Error as IoError, Result as IoResult},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering::SeqCst},
+        Mutex,
+    },
+};
+
+const LRADC_BASE: i64 = 0x8005_0000;
+const PINCTRL_BASE: i64 = 0x8001_8000;
+const AUDIOOUT_BASE: i64 = 0x8004_8000;
+
+// Bits every block has in its first register
+const SFTRST: u32 = 1 << 31;
+const CLKGATE: u32 = 1 << 30;
+
+const HW_LRADC_CTRL0: usize = 0x000;
+const HW_LRADC_CTRL1: usize = 0x010;
+const HW_LRADC_CTRL2: usize = 0x020;
+const HW_LRADC_CH0: usize = 0x050;
+const HW_LRADC_CTRL4: usize = 0x140;
+const LRADC_TOGGLE: u32 = 1 << 31;
+const TEMPSENSE_PWD: u32 = 1 << 15;
+
+const HW_PINCTRL_MUXSEL0: usize = 0x100;
+const HW_PINCTRL_DOUT0: usize = 0x500;
+const HW_PINCTRL_DIN0: usize = 0x600;
+const HW_PINCTRL_DOE0: usize = 0x700;
+
+const HW_AUDIOOUT_CTRL: usize = 0x00;
+const HW_AUDIOOUT_DACDEBUG: usize = 0x40;
+const HW_AUDIOOUT_DATA: usize = 0xF0;
+const AUDIOOUT_RUN: u32 = 1;
+const DAC_READY: u32 = 1 << 1;
+
+/// (bank, pin) of the LED's red, green and blue pins
+const LED_PINS: [(usize, usize); 3] = [(0, 31), (0, 30), (1, 28)];
+/// (bank, pin) of the button
+const BUTTON_PIN: (usize, usize) = (1, 7);
+
+/// The physical LRADC channels of the input and the temperature sensor
+const INPUT_CHANNEL: u32 = 0;
+const PMOS_THIN: u32 = 8;
+const NMOS_THIN: u32 = 9;
+/// What the PMOS side of the temperature sensor reads, in millivolts
+const PMOS_MV: u32 = 400;
+
+/// Whether [`start`] was called (checked on every access, so it's kept
+/// out of the lock)
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static SOC: Mutex<Option<Soc>> = Mutex::new(None);
+
+// What comes from stdin
+static INPUT_MV: AtomicU32 = AtomicU32::new(0);
+static PRESSED: AtomicBool = AtomicBool::new(false);
+/// In Kelvin, as [`f32::to_bits`] (25 °C to start with)
+static TEMP: AtomicU32 = AtomicU32::new(0x4395_1333);
+static LOOPBACK: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Block {
+    Lradc,
+    Pinctrl,
+    Audioout,
+}
+
+/// The simulated registers, and what was last logged.
+struct Soc {
+    lradc: Vec<u32>,
+    pinctrl: Vec<u32>,
+    audioout: Vec<u32>,
+    /// Whether red, green and blue are lit
+    led: [bool; 3],
+    output: u16,
+}
+
+impl Soc {
+    fn new() -> Self {
+        let block = || vec![0; MAP_SIZE / 4 + 1];
+        let mut soc = Self {
+            lradc: block(),
+            pinctrl: block(),
+            audioout: block(),
+            led: [false; 3],
+            output: 0,
+        };
+        soc.lradc[HW_LRADC_CTRL0 / 4] = CLKGATE;
+        soc.lradc[HW_LRADC_CTRL2 / 4] = TEMPSENSE_PWD;
+        soc.lradc[HW_LRADC_CTRL4 / 4] = 0x7654_3000;
+        let (bank, pin) = BUTTON_PIN;
+        soc.pinctrl[muxsel(bank, pin).0 / 4] |= 0b11 << muxsel(bank, pin).1;
+        let (bank, pin) = LED_PINS[0];
+        soc.pinctrl[(HW_PINCTRL_DOE0 + bank * 0x10) / 4] |= 1 << pin;
+        soc.audioout[HW_AUDIOOUT_CTRL / 4] = AUDIOOUT_RUN;
+        soc
+    }
+
+    fn regs(&mut self, block: Block) -> &mut Vec<u32> {
+        match block {
+            Block::Lradc => &mut self.lradc,
+            Block::Pinctrl => &mut self.pinctrl,
+            Block::Audioout => &mut self.audioout,
+        }
+    }
+
+    /// Which block a pointer from [`map`] is.
+    fn block(&mut self, page: *mut u32) -> Option<Block> {
+        [Block::Lradc, Block::Pinctrl, Block::Audioout]
+            .into_iter()
+            .find(|&block| self.regs(block).as_mut_ptr() == page)
+    }
+
+    fn read(&mut self, block: Block, offset: usize) -> u32 {
+        let register = offset & !0xF;
+        match block {
+            Block::Pinctrl if (HW_PINCTRL_DIN0..HW_PINCTRL_DIN0 + 0x30).contains(&register) => {
+                self.din((register - HW_PINCTRL_DIN0) / 0x10)
+            }
+            Block::Audioout if register == HW_AUDIOOUT_DACDEBUG && self.dac_running() => {
+                self.audioout[register / 4] ^= DAC_READY;
+                self.audioout[register / 4]
+            }
+            _ => self.regs(block)[register / 4],
+        }
+    }
+
+    fn write(&mut self, block: Block, offset: usize, value: u32) {
+        let register = offset & !0xF;
+        if block == Block::Pinctrl && (HW_PINCTRL_DIN0..HW_PINCTRL_DIN0 + 0x30).contains(&register)
+        {
+            return; // read only
+        }
+
+        let slot = &mut self.regs(block)[register / 4];
+        *slot = match offset & 0xC {
+            0x0 => value,
+            0x4 => *slot | value,
+            0x8 => *slot & !value,
+            _ => *slot ^ value,
+        };
+
+        match block {
+            Block::Lradc if register == HW_LRADC_CTRL0 => self.convert(),
+            Block::Pinctrl => self.update_led(),
+            Block::Audioout if register == HW_AUDIOOUT_DATA => self.update_output(),
+            _ => {}
+        }
+    }
+
+    /// Converts the channels scheduled in `HW_LRADC_CTRL0`.
+    fn convert(&mut self) {
+        let ctrl0 = self.lradc[HW_LRADC_CTRL0 / 4];
+        let schedule = ctrl0 & 0xFF;
+        if schedule == 0 {
+            return;
+        }
+        if ctrl0 & (SFTRST | CLKGATE) != 0 {
+            eprintln!("simulated LRADC: conversion scheduled while gated or in reset");
+            return;
+        }
+
+        let ctrl2 = self.lradc[HW_LRADC_CTRL2 / 4];
+        let ctrl4 = self.lradc[HW_LRADC_CTRL4 / 4];
+        for channel in (0..8).filter(|channel| schedule & (1 << channel) != 0) {
+            let physical = (ctrl4 >> (channel * 4)) & 0xF;
+            let millivolts = voltage(physical, ctrl2 & TEMPSENSE_PWD == 0);
+            // 1.85 V full scale, twice that with the divider
+            let full_scale = if ctrl2 & (1 << (24 + channel)) != 0 {
+                3700
+            } else {
+                1850
+            };
+            let count = (millivolts * 4096 / full_scale).min(0xFFF);
+
+            let slot = &mut self.lradc[HW_LRADC_CH0 / 4 + channel * 4];
+            *slot = ((*slot ^ LRADC_TOGGLE) & LRADC_TOGGLE) | count;
+            self.lradc[HW_LRADC_CTRL1 / 4] |= 1 << channel;
+        }
+        self.lradc[HW_LRADC_CTRL0 / 4] &= !schedule;
+    }
+
+    /// Whether a pin is a GPIO output (and if so, whether it's high).
+    fn driven(&self, (bank, pin): (usize, usize)) -> Option<bool> {
+        let (register, shift) = muxsel(bank, pin);
+        let is_gpio = (self.pinctrl[register / 4] >> shift) & 0b11 == 0b11;
+        let enabled = self.pinctrl[(HW_PINCTRL_DOE0 + bank * 0x10) / 4] & (1 << pin) != 0;
+        (is_gpio && enabled)
+            .then(|| self.pinctrl[(HW_PINCTRL_DOUT0 + bank * 0x10) / 4] & (1 << pin) != 0)
+    }
+
+    /// `HW_PINCTRL_DINn`: what drives each pin, or the outside world.
+    fn din(&self, bank: usize) -> u32 {
+        (0..32)
+            .map(|pin| {
+                let high = self.driven((bank, pin)).unwrap_or_else(|| {
+                    // Only the button is connected to anything
+                    (bank, pin) == BUTTON_PIN && !PRESSED.load(SeqCst)
+                });
+                u32::from(high) << pin
+            })
+            .sum()
+    }
+
+    fn update_led(&mut self) {
+        let led = LED_PINS.map(|pin| self.driven(pin) == Some(false));
+        if led != self.led {
+            self.led = led;
+            let color = match led {
+                [false, false, false] => "off",
+                [true, false, false] => "red",
+                [false, true, false] => "green",
+                [false, false, true] => "blue",
+                [true, false, true] => "purple",
+                [false, true, true] => "teal",
+                [true, true, false] => "yellow",
+                [true, true, true] => "white",
+            };
+            eprintln!("simulated LED: {color}");
+        }
+    }
+
+    fn dac_running(&self) -> bool {
+        let ctrl = self.audioout[HW_AUDIOOUT_CTRL / 4];
+        ctrl & (SFTRST | CLKGATE) == 0 && ctrl & AUDIOOUT_RUN != 0
+    }
+
+    fn update_output(&mut self) {
+        if !self.dac_running() {
+            return;
+        }
+        // Samples are signed, the output isn't
+        let output = (self.audioout[HW_AUDIOOUT_DATA / 4] as u16) ^ 0x8000;
+        if LOOPBACK.load(SeqCst) {
+            INPUT_MV.store(loopback(output), SeqCst);
+        }
+        if output != self.output {
+            self.output = output;
+            eprintln!("simulated output: {output}");
+        }
+    }
+}
+
+/// The `HW_PINCTRL_MUXSELn` register and bit for a pin.
+fn muxsel(bank: usize, pin: usize) -> (usize, usize) {
+    (
+        HW_PINCTRL_MUXSEL0 + (bank * 2 + pin / 16) * 0x10,
+        (pin % 16) * 2,
+    )
+}
+
+/// The voltage on a physical LRADC channel, in millivolts.
+fn voltage(channel: u32, tempsense: bool) -> u32 {
+    match channel {
+        INPUT_CHANNEL => INPUT_MV.load(SeqCst),
+        PMOS_THIN if tempsense => PMOS_MV,
+        NMOS_THIN if tempsense => {
+            // Whatever makes adc::read_temp (which expects the divider on)
+            // come out right
+            let kelvin = f32::from_bits(TEMP.load(SeqCst)).max(0.0);
+            let counts = kelvin * 4.0 / 1.012;
+            PMOS_MV + (counts * 3700.0 / 4096.0) as u32
+        }
+        _ => 0,
+    }
+}
+
+/// The voltage on the input's ADC pin when the output is wired to it, in
+/// millivolts. The output bit gives 0.1-4.9 V instead of 0-5 V, and the
+/// input divides that down to 0.17-1.57 V at the pin.
+fn loopback(output: u16) -> u32 {
+    let signal = 100.0 + f64::from(output) * 4800.0 / 65535.0;
+    (170.0 + signal * 0.28) as u32
+}
+
+/// Carries out a command from stdin.
+fn command(name: &str, value: &str) -> Result<(), String> {
+    match name {
+        "input" => {
+            let millivolts = value.parse().map_err(|_| "bad voltage")?;
+            INPUT_MV.store(millivolts, SeqCst);
+        }
+        "button" => PRESSED.store(parse_pressed(value)?, SeqCst),
+        "loopback" => LOOPBACK.store(parse_pressed(value)?, SeqCst),
+        "temp" => {
+            let celsius: f32 = value.parse().map_err(|_| "bad temperature")?;
+            TEMP.store((celsius + ZERO_CELSIUS).to_bits(), SeqCst);
+        }
+        _ => return Err(format!("unknown command {name}")),
+    }
+    Ok(())
+}
+
+/// Switches [`mem`](super::mem) over to the simulated registers.
+pub fn start() {
+    reset();
+    read_commands(command);
+}
+
+/// Starts over with the registers the way they are at boot.
+fn reset() {
+    *SOC.lock().unwrap() = Some(Soc::new());
+    RUNNING.store(true, SeqCst);
+}
+
+/// Whether [`start`] was called.
+pub fn is_running() -> bool {
+    RUNNING.load(SeqCst)
+}
+
+/// Maps the block at `offset` (`None` if the simulator isn't running).
+pub fn map(offset: i64) -> Option<IoResult<*mut u32>> {
+    if !RUNNING.load(SeqCst) {
+        return None;
+    }
+    let block = match offset {
+        LRADC_BASE => Block::Lradc,
+        PINCTRL_BASE => Block::Pinctrl,
+        AUDIOOUT_BASE => Block::Audioout,
+        _ => {
+            return Some(Err(IoError::other(format!(
+                "nothing simulated at {offset:#x}"
+            ))))
+        }
+    };
+    let mut soc = SOC.lock().unwrap();
+    Some(Ok(soc.as_mut()?.regs(block).as_mut_ptr()))
+}
+
+/// Reads a simulated register (`None` if the simulator isn't running).
+pub fn peek(page: *mut u32, offset: usize) -> Option<u32> {
+    if !RUNNING.load(SeqCst) {
+        return None;
+    }
+    let mut guard = SOC.lock().unwrap();
+    let soc = guard.as_mut()?;
+    let block = soc.block(page)?;
+    Some(soc.read(block, offset))
+}
+
+/// Writes a simulated register. Returns `false` if the simulator isn't
+/// running.
+pub fn poke(page: *mut u32, offset: usize, value: u32) -> bool {
+    if !RUNNING.load(SeqCst) {
+        return false;
+    }
+    let mut guard = SOC.lock().unwrap();
+    let Some(soc) = guard.as_mut() else {
+        return false;
+    };
+    let Some(block) = soc.block(page) else {
+        return false;
+    };
+    soc.write(block, offset, value);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hardware::{adc, button, dac, led, mmio::init_drivers},
+        LEDCommand,
+    };
+    use std::{
+        sync::{MutexGuard, Once, PoisonError},
+        thread::sleep,
+        time::{Duration, Instant},
+    };
+
+    /// The drivers are only set up once per process, so every test shares
+    /// one simulator, one test at a time.
+    static SHARED: Mutex<()> = Mutex::new(());
+
+    /// Runs the drivers' `init` (and so their `mem_init`) against the boot
+    /// state the first time.
+    fn setup() -> MutexGuard<'static, ()> {
+        static INIT: Once = Once::new();
+        let guard = SHARED.lock().unwrap_or_else(PoisonError::into_inner);
+        INIT.call_once(|| {
+            reset();
+            let initialized = init_drivers(-1);
+            assert!(initialized.adc && initialized.button && initialized.dac && initialized.led);
+        });
+        guard
+    }
+
+    fn with_soc<T>(f: impl FnOnce(&mut Soc) -> T) -> T {
+        f(SOC.lock().unwrap().as_mut().unwrap())
+    }
+
+    #[test]
+    fn mem_init_sets_up_lradc() {
+        let _guard = setup();
+        with_soc(|soc| {
+            assert_eq!(soc.lradc[HW_LRADC_CTRL0 / 4] & (SFTRST | CLKGATE), 0);
+            let ctrl2 = soc.lradc[HW_LRADC_CTRL2 / 4];
+            assert_eq!(ctrl2 & TEMPSENSE_PWD, 0);
+            assert_eq!(
+                (ctrl2 >> 24) & 0b111,
+                0b111,
+                "DIVIDE_BY_TWO on channels 0-2"
+            );
+            let selects = soc.lradc[HW_LRADC_CTRL4 / 4] & 0xFFF;
+            assert_eq!(selects, NMOS_THIN << 8 | PMOS_THIN << 4 | INPUT_CHANNEL);
+        });
+    }
+
+    #[test]
+    fn mem_init_sets_up_led_pins() {
+        let _guard = setup();
+        with_soc(|soc| {
+            for pin in LED_PINS {
+                assert!(soc.driven(pin).is_some(), "LED pin {pin:?} isn't an output");
+            }
+        });
+    }
+
+    #[test]
+    fn reads_input() {
+        let _guard = setup();
+        // 925 mV is a quarter of full scale with the divider on
+        INPUT_MV.store(925, SeqCst);
+        assert_eq!(adc::read_raw(), 1024);
+        INPUT_MV.store(0, SeqCst);
+        assert_eq!(adc::read_raw(), 0);
+    }
+
+    #[test]
+    fn reads_temperature() {
+        let _guard = setup();
+        TEMP.store((40.0 + ZERO_CELSIUS).to_bits(), SeqCst);
+        let celsius = adc::read_temp() - ZERO_CELSIUS;
+        assert!((celsius - 40.0).abs() < 1.0, "read {celsius} °C");
+    }
+
+    #[test]
+    fn reads_button() {
+        let _guard = setup();
+        PRESSED.store(true, SeqCst);
+        assert!(button::read());
+        PRESSED.store(false, SeqCst);
+        assert!(!button::read());
+    }
+
+    #[test]
+    fn sets_output() {
+        let _guard = setup();
+        dac::set(700);
+        assert_eq!(with_soc(|soc| soc.output), 700);
+        dac::set(0);
+        assert_eq!(with_soc(|soc| soc.output), 0);
+    }
+
+    #[test]
+    fn sets_led() {
+        let _guard = setup();
+        // The LED thread only looks at its commands every half a second
+        let wait_for = |expected: [bool; 3]| {
+            let start = Instant::now();
+            while with_soc(|soc| soc.led) != expected {
+                assert!(
+                    start.elapsed() < Duration::from_secs(3),
+                    "LED isn't {expected:?}"
+                );
+                sleep(Duration::from_millis(50));
+            }
+        };
+
+        led::set(LEDCommand::Red);
+        led::set(LEDCommand::Hold);
+        wait_for([true, false, false]);
+        led::set(LEDCommand::Teal);
+        wait_for([false, true, true]);
+        led::set(LEDCommand::Off);
+        wait_for([false, false, false]);
+    }
+}
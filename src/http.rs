@@ -0,0 +1,284 @@
+// This file is part of cloudbit-software.
+//
+// cloudbit-software - an alternative software for the littleBits cloudBit.
+//
+// Copyright (C) 2024 littleBitsman
+//
+// cloudbit-software is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// cloudbit-software is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see https://www.gnu.org/licenses/.
+
+//! Local HTTP API
+//!
+//! With [`Config::http_port`] set, scripts can read and set the hardware
+//! with plain HTTP requests, at the same time as the connection to the
+//! server:
+//! - `GET /input`: `{"value": <input>, "raw": <count>, "filtered": <count>}`
+//! - `POST /output` with `{"value": <output>}` (or just the number)
+//! - `POST /led` with `{"led_command": "<commands>"}` (or just the commands)
+//! - `GET /button`: `{"button": <pressed>}`
+//! - `GET /stats`: the system stats from 0xF4 and whether the cloudBit is
+//!   connected to a server
+//! - `GET /filter`: the [input filter](crate::filter) in use
+//! - `POST /filter` with a new input filter, which is saved like CONFIG
+//!   (0xF5) would
+//!
+//! If [`Config::http_token`] is set, requests need it in an
+//! `Authorization: Bearer <token>` header.
+//!
+//! Every response is JSON, and every connection handles one request.
+
+use crate::{
+    config::{self, Config},
+    connection, filter,
+    handler::system_stats,
+    hardware, input, runtime, LEDCommand,
+};
+use httparse::{Request, Status, EMPTY_HEADER};
+use serde_json::{from_slice, json as serde_json, Value as JsonValue};
+use std::{
+    io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult},
+    net::Ipv4Addr,
+    str::from_utf8,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    spawn,
+    time::timeout,
+};
+
+/// The biggest request accepted (headers and body)
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// How long a client gets to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A parsed request.
+struct HttpRequest {
+    method: String,
+    path: String,
+    /// The token from the `Authorization` header, if any
+    token: Option<String>,
+    body: Vec<u8>,
+}
+
+/// A response: the status code and the JSON body.
+type Response = (u16, JsonValue);
+
+/// Starts the HTTP API on [`Config::http_port`], if it's set.
+pub fn start() {
+    let Some(port) = config::get().http_port else {
+        return;
+    };
+
+    spawn(async move {
+        let listener = match TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).await {
+            Ok(v) => v,
+            Err(err) => {
+                eprintln!("failed to start HTTP API on port {port}: {err}");
+                return;
+            }
+        };
+        eprintln!("HTTP API on port {port}");
+
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    spawn(serve(stream));
+                }
+                Err(err) => eprintln!("failed to accept HTTP client: {err}"),
+            }
+        }
+    });
+}
+
+/// Answers one request.
+async fn serve(mut stream: TcpStream) {
+    let (status, body) = match timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Ok(request)) => respond(request, config::get()).await,
+        Ok(Err(response)) => response,
+        Err(_) => error(408, "request timed out"),
+    };
+
+    let body = body.to_string();
+    let response = format!(
+        "HTTP/1.1 {status} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        reason(status),
+        body.len()
+    );
+    if let Err(err) = stream.write_all(response.as_bytes()).await {
+        eprintln!("failed to answer HTTP request: {err}");
+    }
+    let _ = stream.shutdown().await;
+}
+
+/// Reads and parses a request (or returns the error response for it).
+async fn read_request(stream: &mut TcpStream) -> Result<HttpRequest, Response> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 1024];
+
+    loop {
+        let read = read_some(stream, &mut chunk).await?;
+        buffer.extend_from_slice(&chunk[..read]);
+
+        let mut headers = [EMPTY_HEADER; 32];
+        let mut request = Request::new(&mut headers);
+        let header_size = match request.parse(&buffer) {
+            Ok(Status::Complete(size)) => size,
+            Ok(Status::Partial) if buffer.len() < MAX_REQUEST_SIZE => continue,
+            Ok(Status::Partial) => return Err(error(413, "request too big")),
+            Err(err) => return Err(error(400, &err.to_string())),
+        };
+
+        let header = |name: &str| {
+            request
+                .headers
+                .iter()
+                .find(|v| v.name.eq_ignore_ascii_case(name))
+                .and_then(|v| from_utf8(v.value).ok())
+        };
+        let body_size: usize = match header("Content-Length").map(str::parse) {
+            Some(Ok(size)) => size,
+            Some(Err(_)) => return Err(error(400, "bad Content-Length")),
+            None => 0,
+        };
+        if header_size + body_size > MAX_REQUEST_SIZE {
+            return Err(error(413, "request too big"));
+        }
+
+        let parsed = HttpRequest {
+            method: request.method.unwrap_or_default().to_string(),
+            path: request.path.unwrap_or_default().to_string(),
+            token: header("Authorization")
+                .and_then(|v| v.strip_prefix("Bearer "))
+                .map(|v| v.trim().to_string()),
+            body: Vec::new(),
+        };
+
+        let mut body = buffer.split_off(header_size);
+        while body.len() < body_size {
+            let read = read_some(stream, &mut chunk).await?;
+            body.extend_from_slice(&chunk[..read]);
+        }
+        body.truncate(body_size);
+
+        return Ok(HttpRequest { body, ..parsed });
+    }
+}
+
+/// Reads at least one byte.
+async fn read_some(stream: &mut TcpStream, chunk: &mut [u8]) -> Result<usize, Response> {
+    let read: IoResult<usize> = match stream.read(chunk).await {
+        Ok(0) => Err(IoError::from(IoErrorKind::UnexpectedEof)),
+        v => v,
+    };
+    read.map_err(|err| error(400, &err.to_string()))
+}
+
+/// Compares the token without giving away how much of it matched.
+fn token_matches(expected: &str, given: Option<&str>) -> bool {
+    let given = given.unwrap_or_default();
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Reads a request body: JSON with `key` in it, or just the value.
+fn body_value(body: &[u8], key: &str) -> JsonValue {
+    match from_slice::<JsonValue>(body) {
+        Ok(JsonValue::Object(mut obj)) => obj.remove(key).unwrap_or_default(),
+        Ok(value) => value,
+        Err(_) => from_utf8(body)
+            .map(|v| JsonValue::from(v.trim()))
+            .unwrap_or_default(),
+    }
+}
+
+async fn respond(request: HttpRequest, config: &Config) -> Response {
+    if let Some(token) = &config.http_token {
+        if !token_matches(token, request.token.as_deref()) {
+            return error(401, "missing or wrong token");
+        }
+    }
+
+    let path = request.path.split('?').next().unwrap_or_default();
+    match (request.method.as_str(), path) {
+        ("GET", "/input") => {
+            // As last read by the IO loop, so it's the same as INPUT
+            let (raw, filtered) = filter::last();
+            (
+                200,
+                serde_json!({
+                    "value": input::convert(filtered),
+                    "raw": raw,
+                    "filtered": filtered
+                }),
+            )
+        }
+        ("POST", "/output") => match body_value(&request.body, "value").as_u64() {
+            Some(value) => {
+                let value = value.min(u16::MAX.into()) as u16;
+                hardware::get().set_output(value);
+                (200, serde_json!({ "value": value }))
+            }
+            None => error(400, "expected an output value"),
+        },
+        ("POST", "/led") => match body_value(&request.body, "led_command").as_str() {
+            Some(command) => {
+                hardware::get().set_leds(LEDCommand::parse_chain(command));
+                (200, serde_json!({ "led_command": command }))
+            }
+            None => error(400, "expected LED commands"),
+        },
+        ("GET", "/button") => (
+            200,
+            serde_json!({ "button": hardware::get().read_button() }),
+        ),
+        ("GET", "/stats") => {
+            let mut stats = system_stats().await;
+            stats["connected"] = connection::is_connected().into();
+            (200, stats)
+        }
+        ("GET", "/filter") => (200, serde_json!(filter::get())),
+        ("POST", "/filter") => match from_slice::<JsonValue>(&request.body) {
+            Ok(value) => match runtime::set_input_filter(&value) {
+                Ok(()) => (200, serde_json!(filter::get())),
+                Err(err) => error(400, &err),
+            },
+            Err(_) => error(400, "expected an input filter"),
+        },
+        (_, "/input" | "/output" | "/led" | "/button" | "/stats" | "/filter") => {
+            error(405, "method not allowed")
+        }
+        _ => error(404, "not found"),
+    }
+}
+
+fn error(status: u16, message: &str) -> Response {
+    (status, serde_json!({ "error": message }))
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        413 => "Payload Too Large",
+        _ => "",
+    }
+}
@@ -0,0 +1,156 @@
+// This file is part of cloudbit-software.
+//
+// cloudbit-software - an alternative software for the littleBits cloudBit.
+//
+// Copyright (C) 2024 littleBitsman
+//
+// cloudbit-software is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// cloudbit-software is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see https://www.gnu.org/licenses/.
+
+//! How the input is reported
+//!
+//! The ADC gives a raw 12-bit count. INPUT, SAMPLES and the HTTP API report
+//! it in one [`Format`], picked with `input_format` (in the settings file,
+//! or with CONFIG (0xF5) at runtime). A connection can also pick its own
+//! with `input_format` in HELLO (0x6):
+//! - `raw`: the count as is (0-4095)
+//! - `scaled`: the input's range stretched to 0-65535 (the default, same
+//!   range as OUTPUT)
+//! - `percent`: 0-100
+//! - `millivolts`: the voltage of the littleBits signal (0-5000)
+//!
+//! Change detection and the offline buffer work on raw counts, so the
+//! format doesn't change when INPUT is sent. INPUT and SAMPLES packets are
+//! built with raw counts too, and [`convert_packet`] converts them for each
+//! connection as they're sent.
+
+use crate::hardware::adc::{self, RAW_MAX};
+use serde::Deserialize;
+use serde_json::{json as serde_json, Value as JsonValue};
+use std::sync::atomic::{AtomicU8, Ordering::SeqCst};
+
+/// Every format, in the order of their numbers in [`FORMAT`]
+const FORMATS: [Format; 4] = [
+    Format::Raw,
+    Format::Scaled,
+    Format::Percent,
+    Format::Millivolts,
+];
+
+static FORMAT: AtomicU8 = AtomicU8::new(1);
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Raw,
+    #[default]
+    Scaled,
+    Percent,
+    Millivolts,
+}
+
+impl Format {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Raw => "raw",
+            Self::Scaled => "scaled",
+            Self::Percent => "percent",
+            Self::Millivolts => "millivolts",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        FORMATS.into_iter().find(|format| format.name() == name)
+    }
+
+    /// The largest value there can be (the smallest is always 0).
+    pub fn max(self) -> u16 {
+        self.convert(RAW_MAX)
+    }
+
+    /// Converts a raw ADC count.
+    pub fn convert(self, raw: u16) -> u16 {
+        match self {
+            Self::Raw => raw,
+            Self::Scaled => adc::scale(raw),
+            Self::Percent => adc::percent(raw),
+            Self::Millivolts => adc::millivolts(raw),
+        }
+    }
+}
+
+/// The format in use.
+pub fn format() -> Format {
+    FORMATS[usize::from(FORMAT.load(SeqCst))]
+}
+
+pub fn set_format(format: Format) {
+    let index = FORMATS.iter().position(|&v| v == format).unwrap();
+    FORMAT.store(index as u8, SeqCst);
+}
+
+/// Converts a raw ADC count to the format in use.
+pub fn convert(raw: u16) -> u16 {
+    format().convert(raw)
+}
+
+/// Converts the raw counts in an INPUT (0x1) or SAMPLES (0xC) packet to
+/// `format` (`None` for the one in use). Other packets are left alone.
+pub fn convert_packet(mut packet: JsonValue, format: Option<Format>) -> JsonValue {
+    let format = format.unwrap_or_else(self::format);
+    let convert = |value: &mut JsonValue| {
+        if let Some(raw) = value.as_u64() {
+            *value = format.convert(raw as u16).into();
+        }
+    };
+
+    match packet["opcode"].as_u64() {
+        Some(0x1) => {
+            if let Some(value) = packet.pointer_mut("/data/value") {
+                convert(value);
+            }
+            if let Some(events) = packet.pointer_mut("/data/events") {
+                for event in events.as_array_mut().into_iter().flatten() {
+                    convert(&mut event["value"]);
+                }
+            }
+        }
+        Some(0xC) => {
+            let delta = packet["delta"] == true;
+            if let Some(samples) = packet["samples"].as_array_mut() {
+                // Deltas are between converted values, so add them back up
+                let (mut raw, mut last) = (0, 0);
+                for sample in samples {
+                    let value = sample.as_i64().unwrap_or_default();
+                    raw = if delta { raw + value } else { value };
+                    let converted = i64::from(format.convert(raw as u16));
+                    *sample = if delta { converted - last } else { converted }.into();
+                    last = converted;
+                }
+            }
+            packet["format"] = format.name().into();
+        }
+        _ => {}
+    }
+    packet
+}
+
+/// The format in use and its range, plus the other formats (for IDENTIFY).
+pub fn describe() -> JsonValue {
+    let format = format();
+    let formats: Vec<&str> = FORMATS.into_iter().map(Format::name).collect();
+    serde_json!({
+        "format": format.name(),
+        "min": 0,
+        "max": format.max(),
+        "formats": formats
+    })
+}
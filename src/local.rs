@@ -0,0 +1,192 @@
+// This file is part of cloudbit-software.
+//
+// cloudbit-software - an alternative software for the littleBits cloudBit.
+//
+// Copyright (C) 2024 littleBitsman
+//
+// cloudbit-software is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// cloudbit-software is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see https://www.gnu.org/licenses/.
+
+//! Local WebSocket server
+//!
+//! With [`config::Config::listen_port`] set, clients can connect straight to the
+//! cloudBit (no server needed) and speak the same protocol as a server
+//! would. Every client gets IDENTIFY when it connects and every INPUT after
+//! that; what clients send goes through [`handler::handle_packet`], just
+//! like packets from the server. Clients are never challenged, so commands
+//! that have to be signed (like CONFIG) are refused.
+//!
+//! Clients start out with JSON and without sequence numbers, and can change
+//! that with HELLO (0x6) whenever they like.
+
+use crate::{
+    clock, config,
+    connection::{Disconnect, Identity, PacketSink},
+    handler::{self, LinkStats},
+    input::{self, Format},
+    protocol,
+    sequence::Sequencer,
+    stream,
+    websocket::WebSocketSender,
+};
+use futures::{
+    channel::mpsc::{channel, Receiver, Sender},
+    StreamExt,
+};
+use serde_json::Value as JsonValue;
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Mutex, MutexGuard},
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    select, spawn,
+};
+use tokio_tungstenite::{accept_async, tungstenite::Message, WebSocketStream};
+
+/// Channels to every connected client
+static CLIENTS: Mutex<Vec<Sender<JsonValue>>> = Mutex::new(Vec::new());
+
+fn clients() -> MutexGuard<'static, Vec<Sender<JsonValue>>> {
+    CLIENTS.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Sends a packet to every connected client. Clients that have fallen too
+/// far behind miss it.
+pub fn broadcast(packet: &JsonValue) {
+    clients().retain_mut(|client| match client.try_send(packet.clone()) {
+        Ok(()) => true,
+        Err(err) => !err.is_disconnected(),
+    });
+}
+
+/// Starts accepting clients on [`config::Config::listen_port`], if it's set.
+pub fn start(identity: &Identity) {
+    let Some(port) = config::get().listen_port else {
+        return;
+    };
+
+    let identity = identity.clone();
+    spawn(async move {
+        let listener = match TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).await {
+            Ok(v) => v,
+            Err(err) => {
+                eprintln!("failed to listen on port {port}: {err}");
+                return;
+            }
+        };
+        eprintln!("accepting local clients on port {port}");
+
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    spawn(serve(stream, addr, identity.clone()));
+                }
+                Err(err) => eprintln!("failed to accept local client: {err}"),
+            }
+        }
+    });
+}
+
+/// Talks to one client until it goes away.
+async fn serve(tcp: TcpStream, addr: SocketAddr, identity: Identity) {
+    let client = match accept_async(tcp).await {
+        Ok(v) => v,
+        Err(err) => {
+            eprintln!("local client {addr} failed to connect: {err}");
+            return;
+        }
+    };
+    eprintln!("local client {addr} connected");
+
+    let (sender, outgoing) = channel(16);
+    let reason = run_client(client, addr, &identity, &sender, outgoing).await;
+    // Don't leave a stream running that this client started
+    stream::release(&sender);
+    eprintln!("local client {addr}: {reason}");
+}
+
+async fn run_client(
+    socket: WebSocketStream<TcpStream>,
+    addr: SocketAddr,
+    identity: &Identity,
+    client: &Sender<JsonValue>,
+    mut outgoing: Receiver<JsonValue>,
+) -> Disconnect {
+    let (tx, mut receiver) = socket.split();
+    let mut sender = WebSocketSender::new(tx);
+    let mut sequencer = Sequencer::new(config::get().resend_history);
+    sequencer.new_session(false);
+    let mut clock = clock::Reference::default();
+    let mut input_format = None;
+    let link = LinkStats {
+        endpoint: addr.to_string(),
+        failures: 0,
+        latency: None,
+    };
+
+    clients().push(client.clone());
+
+    if let Err(reason) = sender.send_packet(&identity.identify()).await {
+        return reason;
+    }
+
+    loop {
+        let result = select! {
+            msg = receiver.next() => match msg {
+                None => return Disconnect::Closed(None),
+                Some(Ok(Message::Close(frame))) => {
+                    return Disconnect::Closed(frame.map(|v| v.to_string()))
+                }
+                Some(Ok(Message::Ping(data))) => sender.send(Message::Pong(data)).await,
+                Some(Ok(Message::Text(data))) => match protocol::decode_text(&data) {
+                    Some(packet) => handle(&mut sender, &mut sequencer, &mut input_format, client, &link, packet).await,
+                    None => Ok(()),
+                },
+                Some(Ok(Message::Binary(data))) => match protocol::decode_binary(&data) {
+                    Some(packet) => handle(&mut sender, &mut sequencer, &mut input_format, client, &link, packet).await,
+                    None => Ok(()),
+                },
+                Some(Ok(_)) => Ok(()),
+                Some(Err(err)) => Disconnect::check(err),
+            },
+            Some(packet) = outgoing.next() => {
+                let packet = input::convert_packet(packet, input_format);
+                let packet = sequencer.stamp(clock.add(packet));
+                sender.send_packet(&packet).await
+            }
+        };
+
+        if let Err(reason) = result {
+            return reason;
+        }
+    }
+}
+
+/// Handles a packet from a client. HELLO is allowed at any time.
+async fn handle(
+    sender: &mut WebSocketSender<TcpStream>,
+    sequencer: &mut Sequencer,
+    input_format: &mut Option<Format>,
+    client: &Sender<JsonValue>,
+    link: &LinkStats,
+    packet: JsonValue,
+) -> Result<(), Disconnect> {
+    if let Some(features) = protocol::parse_hello(&packet) {
+        sequencer.new_session(features.sequence);
+        sender.encoding = features.encoding;
+        *input_format = features.input_format;
+        return Ok(());
+    }
+
+    let replies = handler::handle_packet(packet, client, sequencer, link, None);
+    sender.send_all(replies).await
+}
@@ -26,34 +26,18 @@
 )]
 
 const DEFAULT_URL: &str = "wss://gateway.cloudcontrol.littlebitsman.dev/";
-const LOOP_DELAY_MS: u64 = 10;
 
-/// The minimum amount that the input ADC value must change
-/// before the value is considered "different" (this is an
-/// attempt to reduce the effects of noise from the ADC).
-const INPUT_DELTA_THRESHOLD: u16 = 2;
-
-use futures::{channel::mpsc::channel, SinkExt, StreamExt};
+use futures::{channel::mpsc::channel, future::pending};
 use mac_address::get_mac_address;
-use serde_json::{from_str, json as serde_json, to_string, Value as JsonValue};
+use serde_json::json as serde_json;
 use std::{
+    env::args,
     fmt::{Display, Formatter, Result as FmtResult},
     fs::read_to_string,
-    io::ErrorKind as IoErrorKind,
     panic::set_hook as set_panic_hook,
-    process::id as get_pid,
-    time::Duration,
-};
-use sysinfo::{ProcessesToUpdate, System};
-use tokio::{spawn, time::sleep};
-use tokio_tungstenite::{
-    connect_async,
-    tungstenite::{
-        handshake::client::{generate_key, Request},
-        Error as WebSocketError, Message,
-    },
+    process::exit,
 };
-use url::Url;
+use tokio::spawn;
 
 /// commands for LED as an enum
 #[allow(dead_code)]
@@ -95,6 +79,17 @@ impl TryFrom<String> for LEDCommand {
     }
 }
 
+impl LEDCommand {
+    /// Parses a chain of commands separated by spaces or commas (like
+    /// `"green, blink"`), skipping any that aren't known.
+    fn parse_chain(command: &str) -> Vec<Self> {
+        command
+            .split([' ', ','])
+            .filter_map(|item| Self::try_from(item.trim().to_string()).ok())
+            .collect()
+    }
+}
+
 impl Display for LEDCommand {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
         f.write_str(match self {
@@ -126,291 +121,161 @@ macro_rules! json_str {
 // Hardware wrappers
 mod hardware;
 
-use hardware::*;
+// Settings
+mod config;
+
+// Offline buffer for INPUT events
+mod backlog;
+
+// How the input is reported
+mod filter;
+mod input;
+
+// Measuring the input and output
+mod calibrate;
+
+// Event timestamps
+mod clock;
+
+// Connection to the server
+mod auth;
+mod compression;
+mod connection;
+mod deflate;
+mod handler;
+mod http;
+mod local;
+mod mdns;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod policy;
+mod protocol;
+mod proxy;
+mod runtime;
+mod sequence;
+mod servers;
+mod stream;
+mod tls;
+#[cfg(feature = "udp")]
+mod udp;
+mod websocket;
+
+use backlog::Event;
+use connection::Identity;
+use filter::Filter;
+use servers::Servers;
+use stream::Sampler;
 
 // MAIN LOOP
 #[tokio::main]
 async fn main() {
+    // Anything that panics now is a bug, not a dropped connection (those are
+    // handled by the connection supervisor). Exit the whole process so
+    // systemd restarts it instead of leaving a dead task behind.
+    set_panic_hook(Box::new(|v| {
+        eprintln!("{v}");
+        // Turns out the memory mapping is removed after the process exits lol
+        // hardware::cleanup_all();
+        exit(101)
+    }));
+
     let mac_address = get_mac_address()
         .expect("Failed to get MAC address")
         .expect("Failed to get MAC address");
 
-    // this is like this because the borrow checker gets angry due to a value being dropped
-    // but a reference to it still exists (calling trim on a String borrows it and
-    // creates a &str with a reference to its data, then immediately drops the String)
     let cb_id = read_to_string("/var/lb/id").unwrap_or(String::from("ERROR_READING_ID"));
-    let cb_id = cb_id.trim();
-
-    let default_url: Url = DEFAULT_URL.parse().unwrap();
-
-    // Parse url in /usr/local/lb/cloud_client/server_url if it exists,
-    // use DEFAULT_URL if it doesn't or is not a valid URL.
-    let mut url = read_to_string("/usr/local/lb/cloud_client/server_url")
-        .unwrap_or(DEFAULT_URL.to_string())
-        .parse()
-        .unwrap_or_else(|err| {
-            eprintln!("Error while parsing URL: {err}; falling back to default URL");
-            default_url.clone()
-        });
-
-    // The scheme must be any of these:
-    // - http (converted to ws),
-    // - https (converted to wss),
-    // - ws or wss
-    // If it is not any of those, the error is logged and the DEFAULT_URL is used.
-    // (The Url implementation returns an error if the URL is cannot-be-a-base
-    //  OR its scheme is not http, https, ws, or wss)
-    match url.scheme() {
-        "http" => url.set_scheme("ws").unwrap(),
-        "https" => url.set_scheme("wss").unwrap(),
-        "ws" | "wss" => {}
-        a => {
-            eprintln!("Invalid scheme {a} on cloudbit server URL, falling back to default server");
-            url = default_url
-        }
-    }
 
-    eprintln!(
-        "Attempting to connect to {} ({})",
-        url,
-        url.host_str().unwrap_or("?")
-    );
-
-    // initialize variables
-    let mut current_input: u16 = 0; // current input (0 should be the starting value on any server implementations)
-    let request = Request::get(url.as_str())
-        .header("MAC-Address", mac_address.to_string())
-        .header("CB-Id", cb_id)
-        .header("User-Agent", "littleARCH cloudBit")
-        .header("Host", url.host_str().unwrap())
-        .header("Connection", "Upgrade")
-        .header("Upgrade", "websocket")
-        .header("Sec-Websocket-Version", "13")
-        .header("Sec-Websocket-Key", generate_key())
-        .body(())
-        .unwrap();
-
-    drop(url);
-
-    let client = loop {
-        led::set(LEDCommand::Teal);
-        led::set(LEDCommand::Blink);
-        // I wanted to avoid using Clone here but oh well
-        if let Ok((client, _)) = connect_async(request.clone()).await {
-            break client;
-        } else {
-            led::set(LEDCommand::Red);
-            led::set(LEDCommand::Blink);
-            sleep(Duration::from_secs(2)).await
+    config::load();
+    hardware::calibration::load();
+
+    let args: Vec<String> = args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        [] => {}
+        ["calibrate", step] => exit(calibrate::command(step)),
+        _ => {
+            eprintln!(
+                "usage: cloud_client [calibrate <{}>]",
+                calibrate::STEPS.join("|")
+            );
+            exit(2)
         }
-    };
+    }
 
-    drop(request);
+    runtime::load();
+    backlog::load();
+    backlog::start();
+    let servers = Servers::load();
 
-    // tx: sender used internally, this is done so because tx is not Clone
-    // receiver: receiver from the socket, only 1 copy is needed since its managed by 1 thread only
-    let (mut tx, mut receiver) = client.split();
+    // Hardware comes up once and stays up across reconnects.
+    let hardware = hardware::init();
 
     // sender: sends to rx to be processed to be sent through the WebSocket
-    // rx: receives all messages that need to be sent through the WebSocket via tx
-    let (mut sender, mut rx) = channel(16);
-    let mut sender2 = sender.clone(); // 2 threads are running, each needs their own copy
-
-    eprintln!("Successfully connected");
-
-    tx.send(Message::Text(json_str!({
-        "opcode": 0x3,
-        "mac_address": mac_address.to_string(),
-        "cb_id": cb_id
-    })))
-    .await
-    .unwrap();
-
-    led::set(LEDCommand::Green);
-    led::set(LEDCommand::Hold);
-
-    // Captures: rx, tx
-    // This handles sending messages sent over sender or sender2 to rx
-    // through the WebSocket on tx.
-    let send_loop = spawn(async move {
-        while let Some(msg) = rx.next().await {
-            let result = tx.send(msg).await;
-            match result {
-                Ok(()) => {}
-                Err(err) => match err {
-                    WebSocketError::AlreadyClosed | WebSocketError::ConnectionClosed => {
-                        panic!("connection closed, rebooting to attempt reconnection")
-                    }
-                    WebSocketError::Io(err) => match err.kind() {
-                        IoErrorKind::BrokenPipe | IoErrorKind::ConnectionReset => {
-                            panic!("connection closed, rebooting to attempt reconnection")
-                        }
-                        IoErrorKind::OutOfMemory => panic!("!! OUT OF MEMORY !!"),
-                        IoErrorKind::Interrupted => {
-                            panic!("unknown interrupt, rebooting to attempt reconnection")
-                        }
-                        _ => {}
-                    },
-                    e => eprintln!("error on WebSocket: {e}"),
-                },
+    // rx: receives all messages that need to be sent through the WebSocket,
+    //     drained by the connection supervisor while connected
+    let (sender, mut rx) = channel(16);
+    let mut sender2 = sender.clone(); // the IO loop gets its own copy
+
+    // Main IO loop
+    // This never waits on the channel: while offline, changes go to the
+    // offline buffer instead, to be sent after the next IDENTIFY. If the
+    // connection can't keep up, the newest change is held back and retried
+    // every tick (older ones it replaces are skipped).
+    let upstream = config::get().upstream;
+    spawn(async move {
+        let mut current_input: u16 = 0; // current input (0 should be the starting value on any server implementations)
+        let mut sampler = Sampler::new();
+        let mut filter = Filter::new();
+        let mut pending = None; // newest change the channel didn't have room for
+        loop {
+            let right_now = filter.read();
+            if let Some(batch) = sampler.record(right_now) {
+                local::broadcast(&batch);
+                // Samples are live data, so they aren't kept for later
+                if upstream && connection::is_connected() && sender2.try_send(batch).is_err() {
+                    eprintln!("connection can't keep up, dropping a batch of samples");
+                }
             }
-        }
-    });
 
-    // Captures: receiver, sender
-    // This handles receiving and handling messages on receiver.
-    let receive_loop = spawn(async move {
-        // Receive loop
-        while let Some(msg) = receiver.next().await {
-            match msg {
-                Ok(Message::Close(frame_opt)) => {
-                    // we have to exit now to attempt reconnection
-                    if let Some(frame) = frame_opt {
-                        panic!("WebSocket closed: {frame}")
-                    } else {
-                        panic!("WebSocket closed, no close frame was available")
+            if current_input.abs_diff(right_now) > runtime::input_delta_threshold() {
+                current_input = right_now;
+                let packet = clock::stamp(serde_json!({
+                    "opcode": 0x1,
+                    "data": {
+                        // Converted for each connection as it's sent
+                        "value": current_input
                     }
+                }));
+                local::broadcast(&packet);
+
+                if upstream {
+                    pending = Some((packet, Event::now(current_input)));
                 }
-                Ok(Message::Ping(data)) => sender.send(Message::Pong(data)).await.unwrap(),
-                Ok(Message::Text(data)) => {
-                    // eprintln!("{data}");
-                    if let Ok(JsonValue::Object(obj)) = from_str::<JsonValue>(&data) {
-                        match obj["opcode"].as_u64() {
-                            Some(0x2) => {
-                                // OUTPUT
-                                if let Some(new) = obj["data"]["value"].as_u64() {
-                                    dac::set(new as u16);
-                                } else {
-                                    eprintln!("bad output packet: {}", to_string(&obj).unwrap())
-                                }
-                            }
-
-                            // Any numbers that match 0xFX where X is any digit is a developer
-                            // opcode (LED set, button status, etc.)
-
-                            // Set LED
-                            Some(0xF0) => {
-                                if let Some(command) = obj["led_command"].as_str() {
-                                    let command = command.replace(",", " ");
-
-                                    let mut chain = Vec::new();
-
-                                    for item in command.split(" ") {
-                                        if let Ok(cmd) =
-                                            LEDCommand::try_from(item.trim().to_string())
-                                        {
-                                            chain.push(cmd)
-                                        }
-                                    }
-                                    led::set_many(chain);
-                                } else {
-                                    eprintln!("bad set LED packet: {}", json_str!(obj))
-                                }
-                            }
-
-                            // Get button (it is never sent normally)
-                            Some(0xF1) => sender
-                                .send(Message::Text(json_str!({
-                                    "opcode": 0xF2, // 0xF2 is button state (returned from 0xF1)
-                                    "data": {
-                                        "button": button::read()
-                                    }
-                                })))
-                                .await
-                                .unwrap(),
-
-                            // Get system stats (e.g., memory usage, CPU usage)
-                            // Note: you should NOT be polling this
-                            // More notes can be found in protocol details
-                            Some(0xF3) => {
-                                let mut sender = sender.clone();
-                                spawn(async move {
-                                    let mut sysinfo = System::new_all();
-                                    let pid = (get_pid() as usize).into();
-                                    sysinfo.refresh_cpu_usage();
-                                    sysinfo.refresh_memory();
-                                    sysinfo.refresh_processes(ProcessesToUpdate::Some(&[pid]));
-
-                                    sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL).await;
-
-                                    sysinfo.refresh_cpu_usage();
-
-                                    let process = sysinfo.process(pid).unwrap();
-                                    let cpu = process.cpu_usage();
-                                    let mem_bytes = process.memory();
-                                    let total_mem = sysinfo.total_memory();
-                                    let mem_percent =
-                                        ((mem_bytes as f64) / (total_mem as f64)) * 100.0;
-                                    let cpu_temp = adc::read_temp() - 273.15;
-
-                                    // Opcode 0xF4 is system stats (RETURNED from 0xF3)
-                                    sender
-                                        .send(Message::Text(json_str!({
-                                            "opcode": 0xF4,
-                                            "stats": {
-                                                "cpu_usage": cpu,
-                                                "memory_usage": mem_bytes,
-                                                "total_memory": total_mem,
-                                                "memory_usage_percent": mem_percent,
-                                                "cpu_temp": cpu_temp
-                                            }
-                                        })))
-                                        .await
-                                        .unwrap();
-                                });
-                            }
-                            Some(opcode) => eprintln!("invalid opcode: {opcode}"),
-                            None => {}
-                        }
-                    } else {
-                        eprintln!("bad packet from server: {data}")
-                    }
+            }
+
+            if let Some((packet, event)) = pending.take() {
+                if !connection::is_connected() {
+                    backlog::push(event);
+                } else if let Err(err) = sender2.try_send(packet) {
+                    pending = Some((err.into_inner(), event));
                 }
-                Ok(_) => eprintln!("unknown content"),
-                Err(err) => match err {
-                    WebSocketError::Io(err) => match err.kind() {
-                        IoErrorKind::BrokenPipe | IoErrorKind::ConnectionReset => {
-                            panic!("connection closed, rebooting to attempt reconnection")
-                        }
-                        IoErrorKind::OutOfMemory => panic!("!! OUT OF MEMORY !!"),
-                        IoErrorKind::Interrupted => {
-                            panic!("unknown interrupt, rebooting to attempt reconnection")
-                        }
-                        _ => {}
-                    },
-                    e => eprintln!("error on WebSocket: {e}"),
-                },
             }
+            sampler.wait().await
         }
     });
 
-    set_panic_hook(Box::new(move |v| {
-        eprintln!("{v}");
-        send_loop.abort();
-        receive_loop.abort();
-        // Turns out the memory mapping is removed after the process exits lol
-        // hardware::cleanup_all();
-    }));
-
-    hardware::init_all()
-        .map_err(|(origin, err)| format!("failed to initialize {origin}: {err}"))
-        .unwrap();
+    let identity = Identity {
+        mac_address: mac_address.to_string(),
+        cb_id: cb_id.trim().to_string(),
+        hardware,
+    };
 
-    // Main IO loop
-    loop {
-        let right_now = adc::read();
-        if current_input.abs_diff(right_now) > INPUT_DELTA_THRESHOLD {
-            current_input = right_now;
-            sender2
-                .send(Message::Text(json_str!({
-                    "opcode": 0x1,
-                    "data": {
-                        "value": current_input
-                    }
-                })))
-                .await
-                .unwrap();
-        }
-        sleep(Duration::from_millis(LOOP_DELAY_MS)).await
+    local::start(&identity);
+    http::start();
+    mdns::advertise(&identity);
+    if !upstream {
+        // Local clients only, so there's no connection to look after
+        return pending().await;
     }
+
+    connection::supervise(identity, servers, &mut rx, sender).await
 }
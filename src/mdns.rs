@@ -0,0 +1,181 @@
+// This file is part of cloudbit-software.
+//
+// cloudbit-software - an alternative software for the littleBits cloudBit.
+//
+// Copyright (C) 2024 littleBitsman
+//
+// cloudbit-software is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// cloudbit-software is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see https://www.gnu.org/licenses/.
+
+//! mDNS (Bonjour/Avahi)
+//!
+//! The cloudBit advertises itself as a `_cloudbit._tcp` service so it can
+//! be found on a LAN without digging its address out of the router. Its
+//! TXT records have `cb_id`, `mac` and `firmware` (the version of this
+//! software); the port is [`Config::listen_port`] (0 if the local server
+//! is off).
+//!
+//! mDNS is also used to look up `.local` server names, and to find a
+//! `_cloudbit-gateway._tcp` service when no server URL is configured. A
+//! gateway can put `scheme` (`ws` if missing) and `path` (`/` if missing)
+//! in its TXT records.
+
+use crate::{
+    config::{self, Config},
+    connection::Identity,
+    servers::parse_url,
+};
+use mdns_sd::{HostnameResolutionEvent, ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::{
+    io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult},
+    net::SocketAddr,
+    sync::OnceLock,
+    time::Duration,
+};
+use tokio::{net::lookup_host, time::timeout};
+use url::{Host, Url};
+
+/// The service type the cloudBit advertises
+pub const SERVICE_TYPE: &str = "_cloudbit._tcp.local.";
+
+/// The service type of gateways to look for when no URL is configured
+pub const GATEWAY_SERVICE_TYPE: &str = "_cloudbit-gateway._tcp.local.";
+
+static DAEMON: OnceLock<Option<ServiceDaemon>> = OnceLock::new();
+
+/// The mDNS responder, started the first time it's needed (`None` if it
+/// couldn't be).
+fn daemon() -> Option<&'static ServiceDaemon> {
+    DAEMON
+        .get_or_init(|| {
+            ServiceDaemon::new()
+                .inspect_err(|err| eprintln!("failed to start mDNS: {err}"))
+                .ok()
+        })
+        .as_ref()
+}
+
+/// Advertises the cloudBit, if [`Config::mdns`] is on.
+pub fn advertise(identity: &Identity) {
+    let config = config::get();
+    if !config.mdns {
+        return;
+    }
+    let Some(daemon) = daemon() else {
+        return;
+    };
+
+    let mac = identity.mac_address.replace(':', "").to_lowercase();
+    let name = if identity.cb_id.is_empty() {
+        format!("cloudBit {mac}")
+    } else {
+        identity.cb_id.clone()
+    };
+    let properties = [
+        ("cb_id", identity.cb_id.as_str()),
+        ("mac", identity.mac_address.as_str()),
+        ("firmware", env!("CARGO_PKG_VERSION")),
+    ];
+
+    let registered = ServiceInfo::new(
+        SERVICE_TYPE,
+        &name,
+        &format!("cloudbit-{mac}.local."),
+        "",
+        config.listen_port.unwrap_or(0),
+        &properties[..],
+    )
+    .map(ServiceInfo::enable_addr_auto)
+    .and_then(|info| daemon.register(info));
+
+    if let Err(err) = registered {
+        eprintln!("failed to advertise over mDNS: {err}");
+    }
+}
+
+/// Whether `host` is a name that only mDNS knows about.
+pub fn is_local(host: &str) -> bool {
+    host.trim_end_matches('.').ends_with(".local")
+}
+
+/// Looks up the addresses of a `.local` name over mDNS.
+async fn resolve_local(host: &str, config: &Config) -> IoResult<Vec<SocketAddr>> {
+    let not_found = || IoError::new(IoErrorKind::NotFound, format!("{host} not found over mDNS"));
+    let daemon = daemon().ok_or_else(not_found)?;
+
+    let host = format!("{}.", host.trim_end_matches('.'));
+    let events = daemon
+        .resolve_hostname(&host, Some(config.mdns_timeout_ms))
+        .map_err(|err| IoError::other(err.to_string()))?;
+
+    let found = loop {
+        match events.recv_async().await {
+            Ok(HostnameResolutionEvent::AddressesFound(_, addrs)) => break Ok(addrs),
+            Ok(HostnameResolutionEvent::SearchTimeout(_)) | Err(_) => break Err(not_found()),
+            Ok(_) => {}
+        }
+    };
+    let _ = daemon.stop_resolve_hostname(&host);
+
+    Ok(found?
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, 0))
+        .collect())
+}
+
+/// Looks up the addresses of the host in `url`: over mDNS for `.local`
+/// names, the usual way for everything else.
+pub async fn lookup(url: &Url, port: u16) -> IoResult<Vec<SocketAddr>> {
+    let mut addrs = match url.host() {
+        Some(Host::Domain(host)) if is_local(host) => resolve_local(host, config::get()).await?,
+        Some(Host::Domain(host)) => lookup_host((host, port)).await?.collect(),
+        Some(Host::Ipv4(ip)) => vec![SocketAddr::new(ip.into(), port)],
+        Some(Host::Ipv6(ip)) => vec![SocketAddr::new(ip.into(), port)],
+        None => return Err(IoError::new(IoErrorKind::InvalidInput, "URL has no host")),
+    };
+
+    if addrs.is_empty() {
+        return Err(IoError::new(IoErrorKind::NotFound, "host has no addresses"));
+    }
+    for addr in &mut addrs {
+        addr.set_port(port);
+    }
+    Ok(addrs)
+}
+
+/// Looks for a gateway for up to [`Config::mdns_timeout_ms`], returning
+/// the URL of the first one that answers.
+pub async fn discover() -> Option<Url> {
+    let config = config::get();
+    let daemon = daemon()?;
+    let events = daemon
+        .browse(GATEWAY_SERVICE_TYPE)
+        .inspect_err(|err| eprintln!("failed to look for a gateway: {err}"))
+        .ok()?;
+
+    let search = async {
+        loop {
+            match events.recv_async().await {
+                Ok(ServiceEvent::ServiceResolved(info)) => return Some(info),
+                Ok(_) => {}
+                Err(_) => return None,
+            }
+        }
+    };
+    let found = timeout(Duration::from_millis(config.mdns_timeout_ms), search).await;
+    let _ = daemon.stop_browse(GATEWAY_SERVICE_TYPE);
+
+    let info = found.ok().flatten()?;
+    let scheme = info.get_property_val_str("scheme").unwrap_or("ws");
+    let path = info.get_property_val_str("path").unwrap_or("/");
+    let host = info.get_hostname().trim_end_matches('.');
+    parse_url(&format!("{scheme}://{host}:{}{path}", info.get_port()))
+}
@@ -0,0 +1,348 @@
+// This file is part of cloudbit-software.
+//
+// cloudbit-software - an alternative software for the littleBits cloudBit.
+//
+// Copyright (C) 2024 littleBitsman
+//
+// cloudbit-software is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// cloudbit-software is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see https://www.gnu.org/licenses/.
+
+//! MQTT transport (built with the `mqtt` feature)
+//!
+//! Instead of a server, the cloudBit talks to an MQTT broker. Everything
+//! goes through topics under `<base>/<cb_id>`, where `<base>` is the path of
+//! the server URL (`cloudbit` if there isn't one).
+//!
+//! Messages on the command topics are turned into the same packets a
+//! server would send and go through [`handler::handle_packet`] like any
+//! other; packets the cloudBit sends are put on a topic by opcode. Brokers
+//! are never challenged, so commands that have to be signed (like CONFIG)
+//! are refused.
+//!
+//! [`handler::handle_packet`]: crate::handler::handle_packet
+
+use crate::{
+    config,
+    connection::{Disconnect, Identity, PacketSink, Session},
+    mdns,
+    protocol::{self, Encoding, Features},
+    tls,
+};
+use futures::{
+    channel::mpsc::{channel, Receiver},
+    SinkExt, StreamExt,
+};
+use rumqttc::{
+    AsyncClient, ConnectionError, Event, LastWill, MqttOptions, Packet, Publish, QoS,
+    TlsConfiguration, Transport,
+};
+use serde_json::{from_slice, json as serde_json, Value as JsonValue};
+use std::{
+    io::{Error as IoError, Result as IoResult},
+    str::from_utf8,
+    time::Duration,
+};
+use tokio::{select, spawn};
+use url::Url;
+
+/// Topics the cloudBit takes commands on, relative to the device topic
+const COMMAND_TOPICS: &[&str] = &[
+    "output/set",
+    "led/set",
+    "button/get",
+    "stats/get",
+    "stream/set",
+    "command",
+];
+
+/// The features used over MQTT. The broker takes care of delivery, so
+/// there are no sequence numbers.
+const FEATURES: Features = Features {
+    sequence: false,
+    backlog: true,
+    encoding: Encoding::Json,
+    input_format: None,
+};
+
+/// The device topic (`<base>/<cb_id>`) for a server URL.
+fn device_topic(url: &Url, identity: &Identity) -> String {
+    let base = url.path().trim_matches('/');
+    let base = if base.is_empty() { "cloudbit" } else { base };
+    let id = if identity.cb_id.is_empty() {
+        identity.mac_address.replace(':', "")
+    } else {
+        identity.cb_id.clone()
+    };
+    format!("{base}/{id}")
+}
+
+/// Turns a message on a command topic into a packet.
+fn command(topic: &str, payload: &[u8]) -> Option<JsonValue> {
+    let text = || from_utf8(payload).map(str::trim).unwrap_or_default();
+    let packet = match topic {
+        "output/set" => match text().parse::<u64>() {
+            Ok(value) => serde_json!({
+                "opcode": 0x2,
+                "data": {
+                    "value": value
+                }
+            }),
+            Err(err) => {
+                eprintln!("bad output value {:?}: {err}", text());
+                return None;
+            }
+        },
+        "led/set" => serde_json!({
+            "opcode": 0xF0,
+            "led_command": text()
+        }),
+        "button/get" => serde_json!({ "opcode": 0xF1 }),
+        "stats/get" => serde_json!({ "opcode": 0xF3 }),
+        "stream/set" => match from_slice::<JsonValue>(payload) {
+            Ok(JsonValue::Object(mut settings)) => {
+                settings.insert(String::from("opcode"), 0xB.into());
+                JsonValue::Object(settings)
+            }
+            _ => {
+                eprintln!("bad stream settings {:?}", text());
+                return None;
+            }
+        },
+        "command" => return protocol::decode(payload),
+        _ => return None,
+    };
+    Some(packet)
+}
+
+/// Publishes packets on topics under the device topic.
+pub struct MqttSender {
+    client: AsyncClient,
+    topic: String,
+}
+
+impl MqttSender {
+    async fn publish(
+        &self,
+        topic: &str,
+        retain: bool,
+        payload: impl Into<Vec<u8>>,
+    ) -> Result<(), Disconnect> {
+        self.client
+            .publish(
+                format!("{}/{topic}", self.topic),
+                QoS::AtLeastOnce,
+                retain,
+                payload,
+            )
+            .await
+            // This only fails once the event loop is gone
+            .map_err(|_| Disconnect::Closed(None))
+    }
+}
+
+impl PacketSink for MqttSender {
+    // Keeps a backlog message under rumqttc's 10 KiB default limit
+    const BACKLOG_BATCH_SIZE: usize = 128;
+
+    /// - INPUT (0x1): the value on `input` (retained); backlogs also go on
+    ///   `backlog` as the whole packet
+    /// - button state (0xF2): `true` or `false` on `button`
+    /// - system stats (0xF4): the stats on `stats` as JSON
+    /// - SAMPLES (0xC): the whole packet on `samples`
+    /// - anything else: the whole packet on `event`
+    async fn send_packet(&mut self, packet: &JsonValue) -> Result<(), Disconnect> {
+        match packet["opcode"].as_u64() {
+            Some(0x1) => {
+                if packet["backlog"] == true {
+                    self.publish("backlog", false, packet.to_string()).await?;
+                }
+                let value = &packet["data"]["value"];
+                self.publish("input", true, value.to_string()).await
+            }
+            Some(0xF2) => {
+                let button = &packet["data"]["button"];
+                self.publish("button", false, button.to_string()).await
+            }
+            Some(0xC) => self.publish("samples", false, packet.to_string()).await,
+            Some(0xF4) => {
+                let stats = &packet["stats"];
+                self.publish("stats", false, stats.to_string()).await
+            }
+            _ => self.publish("event", false, packet.to_string()).await,
+        }
+    }
+}
+
+/// A connection to the broker, with the command topics subscribed to.
+pub struct MqttLink {
+    sender: MqttSender,
+    /// Messages from the broker, or the error that ended the connection
+    incoming: Receiver<Result<Publish, ConnectionError>>,
+}
+
+/// Connection options for the broker at `url` (credentials come from the
+/// URL too).
+///
+/// rumqttc does its own lookups, so `.local` names are looked up here and
+/// the broker is connected to by address instead.
+async fn options(url: &Url, client_id: String) -> IoResult<MqttOptions> {
+    let tls = url.scheme() == "mqtts";
+    let port = url.port().unwrap_or(if tls { 8883 } else { 1883 });
+    let host = url.host_str().unwrap();
+    let host = if mdns::is_local(host) {
+        mdns::lookup(url, port).await?[0].ip().to_string()
+    } else {
+        host.to_string()
+    };
+
+    let mut options = MqttOptions::new(client_id, host, port);
+    if !url.username().is_empty() {
+        options.set_credentials(url.username(), url.password().unwrap_or_default());
+    }
+    if tls {
+        let config = tls::client_config().map_err(IoError::other)?;
+        options.set_transport(Transport::tls_with_config(TlsConfiguration::Rustls(config)));
+    }
+    Ok(options)
+}
+
+/// Connects to the broker, waits for it to accept the connection and
+/// subscribes to the command topics.
+///
+/// The last will marks the device `offline` on its `status` topic if the
+/// connection drops; `online` and IDENTIFY are published (retained) once
+/// it's up.
+pub async fn connect(url: &Url, identity: &Identity) -> Result<MqttLink, String> {
+    let config = config::get();
+    let topic = device_topic(url, identity);
+    let client_id = format!("cloudbit-{}", identity.mac_address.replace(':', ""));
+
+    let mut options = options(url, client_id)
+        .await
+        .map_err(|err| err.to_string())?;
+    options
+        .set_keep_alive(Duration::from_secs(config.heartbeat_interval_secs))
+        .set_last_will(LastWill::new(
+            format!("{topic}/status"),
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+
+    let (client, mut eventloop) = AsyncClient::new(options, 16);
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => break,
+            Ok(_) => {}
+            Err(err) => return Err(err.to_string()),
+        }
+    }
+
+    // The event loop has to keep being polled for anything to be sent, so
+    // it gets its own task. It ends once `client` is dropped.
+    let (mut tx, incoming) = channel(16);
+    spawn(async move {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    if tx.send(Ok(publish)).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    let _ = tx.send(Err(err)).await;
+                    return;
+                }
+            }
+        }
+    });
+
+    let sender = MqttSender { client, topic };
+    let subscribed = async {
+        for command in COMMAND_TOPICS {
+            sender
+                .client
+                .subscribe(format!("{}/{command}", sender.topic), QoS::AtLeastOnce)
+                .await
+                .map_err(|_| Disconnect::Closed(None))?;
+        }
+        sender.publish("status", true, "online").await?;
+        sender
+            .publish("identify", true, identity.identify().to_string())
+            .await
+    };
+    subscribed.await.map_err(|reason| reason.to_string())?;
+
+    Ok(MqttLink { sender, incoming })
+}
+
+/// Checks whether a broker accepts connections again, without staying
+/// connected to it.
+pub async fn probe(url: &Url, identity: &Identity) -> bool {
+    // A different client ID, so the broker doesn't kick the real connection
+    let client_id = format!("cloudbit-{}-probe", identity.mac_address.replace(':', ""));
+
+    let Ok(options) = options(url, client_id).await else {
+        return false;
+    };
+
+    let (_client, mut eventloop) = AsyncClient::new(options, 1);
+    matches!(
+        eventloop.poll().await,
+        Ok(Event::Incoming(Packet::ConnAck(_)))
+    )
+}
+
+/// Runs one connection to the broker until it drops.
+///
+/// rumqttc does its own keep-alive (every
+/// [`config::Config::heartbeat_interval_secs`]), so the session heartbeat
+/// isn't used here.
+pub async fn run_session(link: MqttLink, mut session: Session<'_>) -> Disconnect {
+    let MqttLink {
+        mut sender,
+        mut incoming,
+    } = link;
+
+    if let Err(reason) = session.start(&mut sender, FEATURES, None).await {
+        return reason;
+    }
+
+    let prefix = format!("{}/", sender.topic);
+    loop {
+        let result = select! {
+            msg = incoming.next() => match msg {
+                None => return Disconnect::Closed(None),
+                Some(Err(err)) => return Disconnect::Mqtt(Box::new(err)),
+                Some(Ok(publish)) => {
+                    let packet = publish
+                        .topic
+                        .strip_prefix(&prefix)
+                        .and_then(|topic| command(topic, &publish.payload));
+                    match packet {
+                        Some(packet) => session.handle(&mut sender, packet).await,
+                        None => Ok(()),
+                    }
+                }
+            },
+            Some(packet) = session.outgoing.next() => {
+                let packet = session.stamp(packet);
+                sender.send_packet(&packet).await
+            }
+            () = session.fail_back.wait() => return Disconnect::FailBack,
+        };
+
+        if let Err(reason) = result {
+            return reason;
+        }
+    }
+}
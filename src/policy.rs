@@ -0,0 +1,108 @@
+// This file is part of cloudbit-software.
+//
+// cloudbit-software - an alternative software for the littleBits cloudBit.
+//
+// Copyright (C) 2024 littleBitsman
+//
+// cloudbit-software is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// cloudbit-software is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see https://www.gnu.org/licenses/.
+
+//! Which server commands are carried out
+//!
+//! Protocol packets (ACK, RESEND, HELLO, CHALLENGE, KEEPALIVE) are always
+//! accepted. Commands (OUTPUT, STREAM and the developer opcodes) are checked
+//! first:
+//! - only opcodes in [`Config::allowed_opcodes`] are carried out (every
+//!   command if it isn't set);
+//! - opcodes in [`Config::signed_opcodes`] also need a `signature`: the hex
+//!   HMAC-SHA256 of the packet without it, keyed with the device secret
+//!   (see [`auth`]). The packet is signed as compact JSON with its keys
+//!   sorted, whichever encoding it came in. It has to have the `nonce` the
+//!   cloudBit sent in AUTH on this connection, so a signed command can't be
+//!   replayed on a later one (and without a CHALLENGE, nothing can be
+//!   signed).
+//!
+//! A rejected command gets an ERROR (0xA) packet back instead of being
+//! carried out.
+
+use crate::{
+    auth,
+    config::{self, Config},
+    protocol::{COMMANDS, OPCODES},
+};
+use serde_json::{json as serde_json, to_string, Value as JsonValue};
+
+/// Whether a command is allowed at all.
+fn allowed(config: &Config, opcode: u64) -> bool {
+    config
+        .allowed_opcodes
+        .as_ref()
+        .is_none_or(|allowed| allowed.contains(&opcode))
+}
+
+/// The opcodes to list in IDENTIFY: every one this build accepts, minus the
+/// commands that aren't allowed.
+pub fn opcodes() -> Vec<u64> {
+    let config = config::get();
+    OPCODES
+        .iter()
+        .copied()
+        .filter(|opcode| !COMMANDS.contains(opcode) || allowed(config, *opcode))
+        .collect()
+}
+
+/// Checks a packet from the server. `nonce` is the one sent in AUTH on this
+/// connection. `Err` is the ERROR packet to send back instead of carrying it
+/// out.
+pub fn check(packet: &JsonValue, nonce: Option<&str>) -> Result<(), JsonValue> {
+    let Some(opcode) = packet["opcode"].as_u64().filter(|v| COMMANDS.contains(v)) else {
+        return Ok(());
+    };
+    let config = config::get();
+
+    let reason = if !allowed(config, opcode) {
+        "opcode not allowed"
+    } else if !config.signed_opcodes.contains(&opcode) {
+        return Ok(());
+    } else if nonce.is_none() {
+        "signed commands need a CHALLENGE first"
+    } else if packet["nonce"].as_str() != nonce {
+        "wrong nonce"
+    } else {
+        match packet["signature"].as_str() {
+            Some(signature) => {
+                let mut unsigned = packet.clone();
+                unsigned.as_object_mut().unwrap().remove("signature");
+                if auth::verify(to_string(&unsigned).unwrap().as_bytes(), signature) {
+                    return Ok(());
+                }
+                "bad signature"
+            }
+            None => "signature required",
+        }
+    };
+
+    eprintln!("rejected opcode {opcode}: {reason}");
+    Err(error(opcode, &packet["seq"], reason))
+}
+
+/// An ERROR (0xA) packet about a packet from the server.
+pub fn error(opcode: u64, seq: &JsonValue, reason: &str) -> JsonValue {
+    let mut packet = serde_json!({
+        "opcode": 0xA,
+        "rejected": opcode,
+        "error": reason
+    });
+    if !seq.is_null() {
+        packet["rejected_seq"] = seq.clone();
+    }
+    packet
+}
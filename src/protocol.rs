@@ -0,0 +1,201 @@
+// This file is part of cloudbit-software.
+//
+// cloudbit-software - an alternative software for the littleBits cloudBit.
+//
+// Copyright (C) 2024 littleBitsman
+//
+// cloudbit-software is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// cloudbit-software is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see https://www.gnu.org/licenses/.
+
+//! Protocol version and feature negotiation
+//!
+//! IDENTIFY tells the server what this build can do. A server that knows
+//! about this answers with HELLO (0x6), picking the features to use for the
+//! connection; a server that doesn't answer in time gets [`Features::LEGACY`].
+//!
+//! Packets are JSON by default. HELLO can switch the connection to
+//! MessagePack instead, which is a lot less work for the cloudBit to build
+//! and smaller on the wire.
+
+use crate::{hardware::Initialized, input::Format};
+use rmp_serde::{from_slice as from_msgpack, to_vec as to_msgpack};
+use serde_json::{from_str, json as serde_json, to_string, Value as JsonValue};
+#[cfg(any(feature = "udp", feature = "mqtt"))]
+use std::str::from_utf8;
+
+/// Bumped whenever the protocol changes in a way servers need to know about.
+/// (The protocol before IDENTIFY carried a version is version 1.)
+pub const PROTOCOL_VERSION: u64 = 2;
+
+/// Features that can be turned on or off with HELLO.
+pub const FEATURES: &[&str] = &["sequence", "backlog"];
+
+/// Encodings the cloudBit can send packets in, for HELLO to pick from.
+pub const ENCODINGS: &[&str] = &["json", "msgpack"];
+
+/// Opcodes this build accepts from the server.
+pub const OPCODES: &[u64] = &[0x2, 0x4, 0x5, 0x6, 0x8, 0xB, 0xF0, 0xF1, 0xF3, 0xF5, 0xF7];
+
+/// The opcodes in [`OPCODES`] that are commands (as opposed to protocol
+/// packets), which [`crate::policy`] can restrict.
+pub const COMMANDS: &[u64] = &[0x2, 0xB, 0xF0, 0xF1, 0xF3, 0xF5, 0xF7];
+
+/// How packets are put on the wire.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// JSON in text frames
+    Json,
+    /// MessagePack in binary frames
+    MessagePack,
+}
+
+impl Encoding {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(Self::Json),
+            "msgpack" => Some(Self::MessagePack),
+            _ => None,
+        }
+    }
+
+    pub fn encode(self, packet: &JsonValue) -> Encoded {
+        match self {
+            Self::Json => Encoded::Text(to_string(packet).unwrap()),
+            Self::MessagePack => Encoded::Binary(to_msgpack(packet).unwrap()),
+        }
+    }
+}
+
+/// An encoded packet, ready to go in a text or binary frame.
+pub enum Encoded {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// Reads a packet from a text frame (JSON).
+pub fn decode_text(data: &str) -> Option<JsonValue> {
+    from_str(data)
+        .inspect_err(|err| eprintln!("bad packet from server: {err}: {data}"))
+        .ok()
+}
+
+/// Reads a packet from a binary frame (MessagePack). Servers may send these
+/// whichever encoding the cloudBit is sending in.
+pub fn decode_binary(data: &[u8]) -> Option<JsonValue> {
+    from_msgpack(data)
+        .inspect_err(|err| eprintln!("bad binary packet from server: {err}"))
+        .ok()
+}
+
+/// Reads a packet from a transport without text and binary frames: JSON if
+/// it starts with `{`, MessagePack otherwise.
+#[cfg(any(feature = "udp", feature = "mqtt"))]
+pub fn decode(data: &[u8]) -> Option<JsonValue> {
+    match data.first() {
+        Some(b'{') => match from_utf8(data) {
+            Ok(text) => decode_text(text),
+            Err(err) => {
+                eprintln!("bad packet from server: {err}");
+                None
+            }
+        },
+        _ => decode_binary(data),
+    }
+}
+
+/// Features in use on a connection.
+#[derive(Clone, Copy)]
+pub struct Features {
+    /// Sequence numbers, ACK (0x4) and RESEND (0x5)
+    pub sequence: bool,
+    /// Replaying INPUT events buffered while offline
+    pub backlog: bool,
+    /// How the cloudBit sends packets
+    pub encoding: Encoding,
+    /// What INPUT and SAMPLES report the input as (`None` for the one in
+    /// the settings)
+    pub input_format: Option<Format>,
+}
+
+impl Features {
+    /// What servers that don't send HELLO get: the wire format from before
+    /// HELLO, with no sequence numbers and no backlog.
+    pub const LEGACY: Self = Self {
+        sequence: false,
+        backlog: false,
+        encoding: Encoding::Json,
+        input_format: None,
+    };
+}
+
+/// The IDENTIFY (0x3) packet.
+pub fn identify(mac_address: &str, cb_id: &str, hardware: Initialized) -> JsonValue {
+    serde_json!({
+        "opcode": 0x3,
+        "mac_address": mac_address,
+        "cb_id": cb_id,
+        "protocol_version": PROTOCOL_VERSION,
+        "firmware_version": env!("CARGO_PKG_VERSION"),
+        "features": FEATURES,
+        "encodings": ENCODINGS,
+        "opcodes": OPCODES,
+        "hardware": {
+            "adc": hardware.adc,
+            "button": hardware.button,
+            "dac": hardware.dac,
+            "led": hardware.led
+        }
+    })
+}
+
+/// Reads a HELLO (0x6) packet. Returns `None` if `packet` is anything else.
+///
+/// Only features listed in HELLO are used; names this build doesn't know
+/// are ignored. The encoding stays JSON unless HELLO picks a known one, and
+/// the input format is the one in the settings unless HELLO picks one.
+pub fn parse_hello(packet: &JsonValue) -> Option<Features> {
+    if packet["opcode"].as_u64() != Some(0x6) {
+        return None;
+    }
+
+    let selected: Vec<&str> = packet["features"]
+        .as_array()
+        .map(|v| v.iter().filter_map(JsonValue::as_str).collect())
+        .unwrap_or_default();
+
+    eprintln!(
+        "server speaks protocol version {}, selected features: {selected:?}",
+        packet["protocol_version"]
+    );
+
+    let encoding = match packet["encoding"].as_str() {
+        Some(name) => Encoding::from_name(name).unwrap_or_else(|| {
+            eprintln!("server picked unknown encoding {name}, using JSON");
+            Encoding::Json
+        }),
+        None => Encoding::Json,
+    };
+
+    let input_format = packet["input_format"].as_str().and_then(|name| {
+        let format = Format::parse(name);
+        if format.is_none() {
+            eprintln!("server picked unknown input format {name}, using the default");
+        }
+        format
+    });
+
+    Some(Features {
+        sequence: selected.contains(&"sequence"),
+        backlog: selected.contains(&"backlog"),
+        encoding,
+        input_format,
+    })
+}
@@ -0,0 +1,334 @@
+// This file is part of cloudbit-software.
+//
+// cloudbit-software - an alternative software for the littleBits cloudBit.
+//
+// Copyright (C) 2024 littleBitsman
+//
+// cloudbit-software is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// cloudbit-software is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see https://www.gnu.org/licenses/.
+
+//! Settings the server can change at runtime
+//!
+//! The server can read and change these with CONFIG (0xF5):
+//! - `loop_delay_ms`, `input_delta_threshold`, `input_format` and
+//!   `input_filter` (kept in the settings file, see [`Config`]), which the
+//!   IO loop picks up right away;
+//! - `server_url`, the server list in [`SERVER_URL_PATH`], which is
+//!   switched to by dropping the connection once the reply is sent.
+//!
+//! `input_filter` can also be changed through the [HTTP API](crate::http),
+//! since CONFIG has to be signed by default.
+//!
+//! Updates are checked as a whole first, so a bad value changes nothing.
+//! Files are written to a copy that's then swapped in, and values are only
+//! applied once they're on disk.
+
+use crate::{
+    config::{self, Config, CONFIG_PATH},
+    filter,
+    input::{self, Format},
+    servers::{self, SERVER_URL_PATH},
+};
+use serde_json::{
+    from_str, json as serde_json, to_string_pretty, Map as JsonMap, Value as JsonValue,
+};
+use std::{
+    fs::{read, read_to_string, remove_file, rename, File},
+    io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult, Write},
+    ops::RangeInclusive,
+    sync::atomic::{AtomicBool, AtomicU16, AtomicU32, Ordering::SeqCst},
+    time::Duration,
+};
+
+/// What `loop_delay_ms` can be set to (it fits in a `u32`, which unlike a
+/// `u64` can be atomic on the cloudBit's ARMv5)
+const LOOP_DELAY_RANGE: RangeInclusive<u64> = 1..=10_000;
+
+/// What `input_delta_threshold` can be set to (in raw ADC counts, which
+/// are 0-4095)
+const INPUT_DELTA_RANGE: RangeInclusive<u64> = 0..=4095;
+
+static LOOP_DELAY_MS: AtomicU32 = AtomicU32::new(10);
+static INPUT_DELTA_THRESHOLD: AtomicU16 = AtomicU16::new(32);
+static SERVERS_CHANGED: AtomicBool = AtomicBool::new(false);
+
+/// Takes the starting values from the settings file (invalid ones are
+/// logged and the defaults kept).
+pub fn load() {
+    let config = config::get();
+    let defaults = Config::default();
+    let starting = |key, value: u64, range, default| {
+        checked(key, &value.into(), range).unwrap_or_else(|err| {
+            eprintln!("Error in {CONFIG_PATH}: {err}; using {default}");
+            default
+        })
+    };
+
+    LOOP_DELAY_MS.store(
+        starting(
+            "loop_delay_ms",
+            config.loop_delay_ms,
+            LOOP_DELAY_RANGE,
+            defaults.loop_delay_ms,
+        ) as u32,
+        SeqCst,
+    );
+    INPUT_DELTA_THRESHOLD.store(
+        starting(
+            "input_delta_threshold",
+            config.input_delta_threshold.into(),
+            INPUT_DELTA_RANGE,
+            defaults.input_delta_threshold.into(),
+        ) as u16,
+        SeqCst,
+    );
+    input::set_format(config.input_format);
+    match config.input_filter.validate() {
+        Ok(()) => filter::set(config.input_filter.clone()),
+        Err(err) => eprintln!("Error in {CONFIG_PATH}: {err}; not filtering the input"),
+    }
+}
+
+/// How long the IO loop sleeps between ADC reads.
+pub fn loop_delay() -> Duration {
+    Duration::from_millis(LOOP_DELAY_MS.load(SeqCst).into())
+}
+
+/// The minimum amount that the input ADC value must change before the
+/// value is considered "different" (this is an attempt to reduce the
+/// effects of noise from the ADC).
+pub fn input_delta_threshold() -> u16 {
+    INPUT_DELTA_THRESHOLD.load(SeqCst)
+}
+
+/// Whether the server list was changed since the connection was made.
+pub fn servers_changed() -> bool {
+    SERVERS_CHANGED.load(SeqCst)
+}
+
+/// [`servers_changed`], clearing it (once the list has been read again).
+pub fn take_servers_changed() -> bool {
+    SERVERS_CHANGED.swap(false, SeqCst)
+}
+
+/// `value` as a number in `range`.
+fn checked(key: &str, value: &JsonValue, range: RangeInclusive<u64>) -> Result<u64, String> {
+    value.as_u64().filter(|v| range.contains(v)).ok_or_else(|| {
+        format!(
+            "{key} must be a number from {} to {}",
+            range.start(),
+            range.end()
+        )
+    })
+}
+
+/// The settings in effect (and the server list that will be used for the
+/// next connection).
+fn effective() -> JsonValue {
+    let urls: Vec<String> = servers::read_urls()
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    serde_json!({
+        "loop_delay_ms": LOOP_DELAY_MS.load(SeqCst),
+        "input_delta_threshold": input_delta_threshold(),
+        "input_format": input::format().name(),
+        "input_filter": filter::get(),
+        "server_url": urls
+    })
+}
+
+/// Writes `path` and waits for it to be on the disk, so a rename after it
+/// can't get there before the data does.
+fn write_synced(path: &str, contents: &[u8]) -> IoResult<()> {
+    let mut file = File::create(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+/// Writes a copy of `path` and then swaps it in, so a power cut mid-write
+/// leaves the old file instead of half of a new one.
+pub fn write_atomically(path: &str, contents: impl AsRef<[u8]>) -> IoResult<()> {
+    let temp = format!("{path}.tmp");
+    write_synced(&temp, contents.as_ref())?;
+    rename(temp, path)
+}
+
+/// The settings file with `settings` set in it, keeping everything else.
+fn settings_file(settings: &[(&str, JsonValue)]) -> IoResult<String> {
+    let mut file: JsonValue = match read_to_string(CONFIG_PATH) {
+        // A broken file is left alone rather than replaced
+        Ok(text) => from_str(&text).map_err(IoError::other)?,
+        Err(err) if err.kind() == IoErrorKind::NotFound => serde_json!({}),
+        Err(err) => return Err(err),
+    };
+    if !file.is_object() {
+        return Err(IoError::other("not a JSON object"));
+    }
+
+    for (key, value) in settings {
+        file[*key] = value.clone();
+    }
+    Ok(to_string_pretty(&file).unwrap())
+}
+
+/// Like [`write_atomically`] for several files: every copy is written
+/// before any is swapped in, and if swapping one in fails, the ones before
+/// it are put back. So a failed write changes none of them, unless putting
+/// them back fails too (which is logged).
+fn write_all_atomically(files: Vec<(&str, String)>) -> Result<(), String> {
+    let remove_temps = |files: &[(&str, String)]| {
+        for (path, _) in files {
+            let _ = remove_file(format!("{path}.tmp"));
+        }
+    };
+
+    // What each file was, to put back (`None` if there wasn't one)
+    let mut old = Vec::new();
+    for (path, contents) in &files {
+        let result = write_synced(&format!("{path}.tmp"), contents.as_bytes()).and_then(|()| {
+            match read(path) {
+                Ok(v) => Ok(Some(v)),
+                Err(err) if err.kind() == IoErrorKind::NotFound => Ok(None),
+                Err(err) => Err(err),
+            }
+        });
+        match result {
+            Ok(v) => old.push(v),
+            Err(err) => {
+                remove_temps(&files);
+                return Err(format!("failed to save {path}: {err}"));
+            }
+        }
+    }
+
+    for (i, (path, _)) in files.iter().enumerate() {
+        if let Err(err) = rename(format!("{path}.tmp"), path) {
+            for ((path, _), old) in files[..i].iter().zip(&old) {
+                let put_back = match old {
+                    Some(contents) => write_atomically(path, contents),
+                    None => remove_file(path),
+                };
+                if let Err(err) = put_back {
+                    eprintln!("failed to put {path} back: {err}");
+                }
+            }
+            remove_temps(&files[i..]);
+            return Err(format!("failed to save {path}: {err}"));
+        }
+    }
+    Ok(())
+}
+
+/// Applies the changes in a CONFIG (0xF5) packet's `config` object (if it
+/// has one) and returns the reply (0xF6) with the settings in effect, plus
+/// an `error` if the changes weren't (all) made.
+pub fn handle(packet: &JsonValue) -> JsonValue {
+    let result = match &packet["config"] {
+        JsonValue::Null => Ok(()),
+        JsonValue::Object(changes) => update(changes),
+        _ => Err(String::from("config must be an object")),
+    };
+
+    let mut reply = serde_json!({
+        "opcode": 0xF6,
+        "config": effective()
+    });
+    if let Err(err) = result {
+        eprintln!("config update failed: {err}");
+        reply["error"] = err.into();
+    }
+    reply
+}
+
+/// Changes `input_filter` just like CONFIG would, for the HTTP API (which
+/// doesn't need CONFIG's signature).
+pub fn set_input_filter(value: &JsonValue) -> Result<(), String> {
+    let mut changes = JsonMap::new();
+    changes.insert(String::from("input_filter"), value.clone());
+    update(&changes)
+}
+
+fn update(changes: &JsonMap<String, JsonValue>) -> Result<(), String> {
+    let mut settings = Vec::new();
+    let mut urls = None;
+    for (key, value) in changes {
+        match key.as_str() {
+            "loop_delay_ms" => settings.push((
+                "loop_delay_ms",
+                checked(key, value, LOOP_DELAY_RANGE)?.into(),
+            )),
+            "input_delta_threshold" => settings.push((
+                "input_delta_threshold",
+                checked(key, value, INPUT_DELTA_RANGE)?.into(),
+            )),
+            "input_format" => {
+                value
+                    .as_str()
+                    .and_then(Format::parse)
+                    .ok_or("input_format must be raw, scaled, percent or millivolts")?;
+                settings.push(("input_format", value.clone()));
+            }
+            "input_filter" => {
+                let parsed = filter::parse(value)?;
+                settings.push(("input_filter", serde_json!(parsed)));
+            }
+            "server_url" => {
+                let list: Vec<&JsonValue> = match value {
+                    JsonValue::Array(list) => list.iter().collect(),
+                    value => vec![value],
+                };
+                let parsed = list
+                    .into_iter()
+                    .map(|v| v.as_str().and_then(servers::parse_url))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| String::from("server_url must be valid server URLs"))?;
+                urls = Some(parsed);
+            }
+            key => return Err(format!("unknown setting {key}")),
+        }
+    }
+
+    // Everything is written before anything is applied
+    let mut files = Vec::new();
+    if !settings.is_empty() {
+        let contents = settings_file(&settings)
+            .map_err(|err| format!("failed to save {CONFIG_PATH}: {err}"))?;
+        files.push((CONFIG_PATH, contents));
+    }
+    if let Some(urls) = &urls {
+        let mut text: String = urls.iter().map(|url| format!("{url}\n")).collect();
+        if text.is_empty() {
+            text.push_str("# looking for a gateway over mDNS\n");
+        }
+        files.push((SERVER_URL_PATH, text));
+    }
+    write_all_atomically(files)?;
+
+    for (key, value) in settings {
+        match key {
+            "loop_delay_ms" => LOOP_DELAY_MS.store(value.as_u64().unwrap() as u32, SeqCst),
+            "input_delta_threshold" => {
+                INPUT_DELTA_THRESHOLD.store(value.as_u64().unwrap() as u16, SeqCst);
+            }
+            "input_format" => {
+                input::set_format(Format::parse(value.as_str().unwrap()).unwrap());
+            }
+            _ => filter::set(filter::parse(&value).unwrap()),
+        }
+        eprintln!("{key} set to {value}");
+    }
+    if urls.is_some() {
+        eprintln!("server_url changed");
+        SERVERS_CHANGED.store(true, SeqCst);
+    }
+    Ok(())
+}
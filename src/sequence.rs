@@ -0,0 +1,180 @@
+// This file is part of cloudbit-software.
+//
+// cloudbit-software - an alternative software for the littleBits cloudBit.
+//
+// Copyright (C) 2024 littleBitsman
+//
+// cloudbit-software is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// cloudbit-software is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see https://www.gnu.org/licenses/.
+
+//! Sequence numbers for packets
+//!
+//! Every packet the cloudBit sends (other than IDENTIFY) gets a `seq` that
+//! counts up from 1 for as long as the process runs, including across
+//! reconnects. The last few INPUT packets are kept so the server can ask for
+//! them again with RESEND (0x5).
+//!
+//! Packets from the server may have their own `seq`. If they do, OUTPUT
+//! packets are acknowledged with ACK (0x4), and duplicates or packets older
+//! than the newest one already seen are acknowledged but not applied.
+//! Servers that never send `seq` never get an ACK.
+//!
+//! All of this can be turned off for a connection with HELLO (the
+//! `sequence` feature).
+
+use serde_json::{json as serde_json, Value as JsonValue};
+use std::collections::VecDeque;
+
+pub struct Sequencer {
+    /// Whether sequence numbers are used on this connection
+    enabled: bool,
+    next: u64,
+    /// Recently sent INPUT packets (already stamped), oldest first
+    history: VecDeque<JsonValue>,
+    history_size: usize,
+    /// Newest `seq` seen from the server on this connection
+    last_received: Option<u64>,
+}
+
+impl Sequencer {
+    pub fn new(history_size: usize) -> Self {
+        Self {
+            enabled: true,
+            next: 1,
+            history: VecDeque::with_capacity(history_size),
+            history_size,
+            last_received: None,
+        }
+    }
+
+    /// Forgets the server's sequence numbers (a new connection may well be a
+    /// different server). The cloudBit's own numbers keep counting.
+    pub fn new_session(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.last_received = None;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Adds the next `seq` to `packet`, remembering it if it's an INPUT.
+    /// Does nothing if sequence numbers are off for this connection.
+    pub fn stamp(&mut self, mut packet: JsonValue) -> JsonValue {
+        if !self.enabled {
+            return packet;
+        }
+
+        if let JsonValue::Object(obj) = &mut packet {
+            obj.insert(String::from("seq"), self.next.into());
+            self.next += 1;
+
+            if obj["opcode"].as_u64() == Some(0x1) && self.history_size > 0 {
+                if self.history.len() == self.history_size {
+                    self.history.pop_front();
+                }
+                self.history.push_back(packet.clone());
+            }
+        }
+        packet
+    }
+
+    /// Checks a `seq` from the server. Returns `false` for duplicates and
+    /// for packets older than the newest one already seen.
+    pub fn receive(&mut self, seq: u64) -> bool {
+        match self.last_received {
+            Some(last) if seq <= last => false,
+            last => {
+                if let Some(last) = last.filter(|last| seq > last + 1) {
+                    eprintln!("missed {} packets from server", seq - last - 1);
+                }
+                self.last_received = Some(seq);
+                true
+            }
+        }
+    }
+
+    /// The server has every INPUT up to `seq`, so they don't need keeping.
+    pub fn acked(&mut self, seq: u64) {
+        while self
+            .history
+            .front()
+            .is_some_and(|v| v["seq"].as_u64().is_some_and(|v| v <= seq))
+        {
+            self.history.pop_front();
+        }
+    }
+
+    /// The remembered INPUT packets with a `seq` in `from..=to`, marked with
+    /// `"resent": true`.
+    pub fn resend(&self, from: u64, to: u64) -> Vec<JsonValue> {
+        // Nothing past the last packet sent was ever there to miss (and the
+        // server picks the numbers, so they can be anything)
+        let to = to.min(self.next - 1);
+        let packets: Vec<JsonValue> = self
+            .history
+            .iter()
+            .filter(|v| v["seq"].as_u64().is_some_and(|v| (from..=to).contains(&v)))
+            .map(|v| {
+                let mut packet = v.clone();
+                packet["resent"] = true.into();
+                packet
+            })
+            .collect();
+
+        let wanted = to.saturating_sub(from).saturating_add(1);
+        if from <= to && (packets.len() as u64) < wanted {
+            eprintln!(
+                "server asked for {wanted} packets, only {} are still kept",
+                packets.len()
+            );
+        }
+        packets
+    }
+}
+
+/// The ACK (0x4) packet for a server `seq`.
+pub fn ack(seq: u64) -> JsonValue {
+    serde_json!({
+        "opcode": 0x4,
+        "ack": seq
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(value: u64) -> JsonValue {
+        serde_json!({ "opcode": 0x1, "data": { "value": value } })
+    }
+
+    #[test]
+    fn resends_what_is_kept() {
+        let mut sequencer = Sequencer::new(2);
+        for value in 0..3 {
+            sequencer.stamp(input(value));
+        }
+        let resent = sequencer.resend(1, 3);
+        let seqs: Vec<_> = resent.iter().map(|v| v["seq"].as_u64().unwrap()).collect();
+        assert_eq!(seqs, [2, 3]);
+        assert!(resent.iter().all(|v| v["resent"] == true));
+    }
+
+    #[test]
+    fn resends_any_range() {
+        let mut sequencer = Sequencer::new(4);
+        sequencer.stamp(input(0));
+        assert_eq!(sequencer.resend(0, u64::MAX).len(), 1);
+        assert_eq!(sequencer.resend(u64::MAX, u64::MAX).len(), 0);
+        assert_eq!(sequencer.resend(u64::MAX, 0).len(), 0);
+    }
+}
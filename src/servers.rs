@@ -0,0 +1,208 @@
+// This file is part of cloudbit-software.
+//
+// cloudbit-software - an alternative software for the littleBits cloudBit.
+//
+// Copyright (C) 2024 littleBitsman
+//
+// cloudbit-software is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// cloudbit-software is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see https://www.gnu.org/licenses/.
+
+//! Server list with failover and backoff
+//!
+//! The first server is the primary one. After [`Config::failover_after`]
+//! failures in a row the next one is tried, wrapping back around to the
+//! primary at the end of the list. Every server has its own exponential
+//! backoff, and every delay is jittered so a fleet of cloudBits doesn't
+//! reconnect to a recovering server all at once.
+
+use crate::{config::Config, mdns, DEFAULT_URL};
+use rand::{thread_rng, Rng};
+use std::{fs::read_to_string, time::Duration};
+use url::Url;
+
+pub const SERVER_URL_PATH: &str = "/usr/local/lb/cloud_client/server_url";
+
+struct Server {
+    url: Url,
+    /// Failed attempts in a row (reset after connecting)
+    failures: u32,
+}
+
+pub struct Servers {
+    servers: Vec<Server>,
+    active: usize,
+    /// Failed attempts on any server since startup
+    total_failures: u64,
+    /// Whether no URLs were configured, so a gateway is looked for over
+    /// mDNS before every attempt
+    discover: bool,
+}
+
+/// Parses a server URL.
+///
+/// The scheme must be any of these:
+/// - http (converted to ws),
+/// - https (converted to wss),
+/// - ws or wss
+/// - udp (only when built with the `udp` feature; the port is required)
+/// - mqtt or mqtts (only when built with the `mqtt` feature)
+///
+/// If it is not any of those, the error is logged and `None` is returned.
+/// (The Url implementation returns an error if the URL is cannot-be-a-base
+///  OR its scheme is not http, https, ws, or wss)
+pub fn parse_url(text: &str) -> Option<Url> {
+    let mut url: Url = match text.parse() {
+        Ok(url) => url,
+        Err(err) => {
+            eprintln!("Error while parsing URL {text}: {err}");
+            return None;
+        }
+    };
+
+    match url.scheme() {
+        "http" => url.set_scheme("ws").unwrap(),
+        "https" => url.set_scheme("wss").unwrap(),
+        "ws" | "wss" => {}
+        "udp" if cfg!(feature = "udp") => {
+            if url.port().is_none() {
+                eprintln!("UDP server URL {url} needs a port");
+                return None;
+            }
+        }
+        "udp" => {
+            eprintln!("UDP server URL {url} given, but this build doesn't support UDP");
+            return None;
+        }
+        "mqtt" | "mqtts" if cfg!(feature = "mqtt") => {}
+        "mqtt" | "mqtts" => {
+            eprintln!("MQTT broker URL {url} given, but this build doesn't support MQTT");
+            return None;
+        }
+        a => {
+            eprintln!("Invalid scheme {a} on cloudbit server URL {url}");
+            return None;
+        }
+    }
+
+    Some(url)
+}
+
+/// Reads the URLs in [`SERVER_URL_PATH`], one per line (lines starting
+/// with `#` are skipped). Invalid URLs are logged and skipped.
+pub fn read_urls() -> Vec<Url> {
+    let text = read_to_string(SERVER_URL_PATH).unwrap_or_default();
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(parse_url)
+        .collect()
+}
+
+/// Adds up to half of `delay` again at random.
+fn jitter(delay: Duration) -> Duration {
+    delay.mul_f64(1.0 + thread_rng().gen_range(0.0..0.5))
+}
+
+impl Servers {
+    /// Reads the server list from [`SERVER_URL_PATH`] (see [`read_urls`]).
+    /// If there are no valid URLs in it, a gateway is looked for over mDNS
+    /// (see [`Self::discover`]).
+    pub fn load() -> Self {
+        Self::new(read_urls())
+    }
+
+    pub fn new(mut urls: Vec<Url>) -> Self {
+        let discover = urls.is_empty();
+        if discover {
+            eprintln!("No valid server URLs, looking for a gateway over mDNS");
+            urls.push(DEFAULT_URL.parse().unwrap());
+        }
+
+        Self {
+            servers: urls
+                .into_iter()
+                .map(|url| Server { url, failures: 0 })
+                .collect(),
+            active: 0,
+            total_failures: 0,
+            discover,
+        }
+    }
+
+    /// If no URLs were configured, looks for a gateway over mDNS and uses
+    /// it. The last gateway found (or [`DEFAULT_URL`] if there never was
+    /// one) is kept if none answers.
+    pub async fn discover(&mut self) {
+        if !self.discover {
+            return;
+        }
+        let Some(url) = mdns::discover().await else {
+            return;
+        };
+
+        let server = &mut self.servers[0];
+        if server.url != url {
+            eprintln!("found gateway {url}");
+            *server = Server { url, failures: 0 };
+        }
+    }
+
+    /// The server to connect to next.
+    pub fn active(&self) -> &Url {
+        &self.servers[self.active].url
+    }
+
+    /// The primary server, if it isn't the active one.
+    pub fn primary_if_inactive(&self) -> Option<&Url> {
+        (self.active != 0).then(|| &self.servers[0].url)
+    }
+
+    pub fn total_failures(&self) -> u64 {
+        self.total_failures
+    }
+
+    /// How long to wait before connecting to [`Self::active`].
+    pub fn delay(&self, config: &Config) -> Duration {
+        let failures = self.servers[self.active].failures;
+        let min = Duration::from_millis(config.backoff_min_ms);
+        let max = Duration::from_millis(config.backoff_max_ms);
+        let delay = min
+            .saturating_mul(1 << failures.saturating_sub(1).min(16))
+            .min(max);
+        jitter(delay)
+    }
+
+    /// Call after connecting to [`Self::active`].
+    pub fn connected(&mut self) {
+        self.servers[self.active].failures = 0;
+    }
+
+    /// Call after failing to connect to [`Self::active`]. Moves on to the
+    /// next server every [`Config::failover_after`] failures in a row.
+    pub fn failed(&mut self, config: &Config) {
+        self.total_failures += 1;
+
+        let server = &mut self.servers[self.active];
+        server.failures += 1;
+
+        let failover = server.failures.is_multiple_of(config.failover_after.max(1));
+        if failover && self.servers.len() > 1 {
+            self.active = (self.active + 1) % self.servers.len();
+            eprintln!("Failing over to {}", self.active());
+        }
+    }
+
+    /// Switches back to the primary server.
+    pub fn fail_back(&mut self) {
+        self.active = 0;
+        self.servers[0].failures = 0;
+    }
+}
@@ -0,0 +1,289 @@
+// This file is part of cloudbit-software.
+//
+// cloudbit-software - an alternative software for the littleBits cloudBit.
+//
+// Copyright (C) 2024 littleBitsman
+//
+// cloudbit-software is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// cloudbit-software is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see https://www.gnu.org/licenses/.
+
+//! Fixed-rate streaming of the input
+//!
+//! Normally INPUT is only sent when the input changes. For plotting a
+//! signal the server can start a stream instead with STREAM (0xB):
+//!
+//! ```json
+//! { "opcode": 11, "rate": 100, "batch": 50, "delta": true }
+//! ```
+//!
+//! The IO loop then reads the input `rate` times a second (instead of every
+//! [`runtime::loop_delay`], and through the [`filter`](crate::filter)) and
+//! sends every `batch` reads as one SAMPLES
+//! (0xC) packet. `"rate": 0` stops the stream, and so does the connection
+//! that started it (the server or a local client) going away. There's only
+//! one stream, so a STREAM from another connection takes it over.
+//!
+//! The IO loop owns the stream; STREAM only swaps the settings here, and
+//! the loop picks them up before its next read.
+
+use crate::{clock, input::Format, runtime};
+use futures::channel::mpsc::Sender;
+use serde_json::{json as serde_json, Value as JsonValue};
+use std::{
+    ops::RangeInclusive,
+    sync::{
+        atomic::{AtomicU32, Ordering::SeqCst},
+        Mutex,
+    },
+    time::Duration,
+};
+use tokio::time::{interval, sleep, Instant, Interval, MissedTickBehavior};
+
+/// What `rate` (samples per second) can be
+const RATE_RANGE: RangeInclusive<u64> = 1..=1000;
+
+/// What `batch` (samples per SAMPLES packet) can be
+const BATCH_RANGE: RangeInclusive<u64> = 1..=1000;
+
+/// The settings the IO loop should be using (`None` = not streaming)
+static SETTINGS: Mutex<Option<Settings>> = Mutex::new(None);
+
+/// The channel to whoever started the stream, to tell which connection it
+/// belongs to (see [`release`])
+static OWNER: Mutex<Option<Sender<JsonValue>>> = Mutex::new(None);
+
+/// Bumped whenever [`SETTINGS`] changes, so the IO loop only has to look
+/// at an atomic on every read. It wraps around, which is fine since it's
+/// only compared for equality (and a `u64` can't be atomic on ARMv5).
+static GENERATION: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Clone, Copy)]
+struct Settings {
+    /// Time between samples, in microseconds
+    interval_us: u64,
+    batch: usize,
+    delta: bool,
+}
+
+fn set(settings: Option<Settings>) {
+    *SETTINGS.lock().unwrap() = settings;
+    GENERATION.fetch_add(1, SeqCst);
+}
+
+/// Stops the stream (if there is one).
+pub fn stop() {
+    *OWNER.lock().unwrap() = None;
+    if SETTINGS.lock().unwrap().is_some() {
+        eprintln!("stopping input stream");
+        set(None);
+    }
+}
+
+/// Stops the stream if `owner` started it (for when that connection goes
+/// away).
+pub fn release(owner: &Sender<JsonValue>) {
+    let owned = OWNER
+        .lock()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|v| v.same_receiver(owner));
+    if owned {
+        stop();
+    }
+}
+
+/// Starts, changes or stops the stream for a STREAM (0xB) packet from the
+/// connection `owner` sends to. `Err` is why the packet was rejected.
+pub fn handle(packet: &JsonValue, owner: &Sender<JsonValue>) -> Result<(), &'static str> {
+    let rate = packet["rate"].as_u64().ok_or("rate must be a number")?;
+    if rate == 0 {
+        stop();
+        return Ok(());
+    }
+    if !RATE_RANGE.contains(&rate) {
+        return Err("rate must be from 1 to 1000");
+    }
+
+    let batch = match &packet["batch"] {
+        JsonValue::Null => rate,
+        value => value
+            .as_u64()
+            .filter(|v| BATCH_RANGE.contains(v))
+            .ok_or("batch must be from 1 to 1000")?,
+    };
+    let delta = packet["delta"].as_bool().unwrap_or(false);
+
+    eprintln!("streaming input at {rate} Hz in batches of {batch}");
+    *OWNER.lock().unwrap() = Some(owner.clone());
+    set(Some(Settings {
+        interval_us: 1_000_000 / rate,
+        batch: batch as usize,
+        delta,
+    }));
+    Ok(())
+}
+
+/// When a stream started, on both clocks
+#[derive(Clone, Copy, Default)]
+struct Start {
+    /// Milliseconds since the Unix epoch (`None` if the clock wasn't synced)
+    wall_ms: Option<u64>,
+    /// Microseconds since boot (see [`clock`])
+    mono_us: u64,
+}
+
+impl Start {
+    fn now() -> Self {
+        Self {
+            wall_ms: clock::synced_wall_ms(),
+            mono_us: clock::monotonic().as_micros() as u64,
+        }
+    }
+}
+
+/// A stream in progress.
+struct Stream {
+    settings: Settings,
+    interval: Interval,
+    /// When the first tick was due
+    first_tick: Option<Instant>,
+    /// The slot on the schedule of the read being recorded
+    slot: u64,
+    /// When the first sample was taken
+    started: Option<Start>,
+    /// How many samples were sent before the ones in `samples`
+    sent: u64,
+    /// Raw ADC counts
+    samples: Vec<u16>,
+}
+
+impl Stream {
+    fn new(settings: Settings) -> Self {
+        let mut interval = interval(Duration::from_micros(settings.interval_us));
+        // A late read is skipped rather than taken in a hurry afterwards,
+        // since it'd be stamped with a time it wasn't read at.
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        Self {
+            settings,
+            interval,
+            first_tick: None,
+            slot: 0,
+            started: None,
+            sent: 0,
+            samples: Vec::with_capacity(settings.batch),
+        }
+    }
+
+    /// The SAMPLES (0xC) packet for the samples collected so far.
+    ///
+    /// Samples are timed by the stream's start and the interval (rather
+    /// than by when each was actually read), so `start` (and `mono_ms`)
+    /// is where the batch falls on that schedule; `start` is left out if
+    /// the clock wasn't synced when the stream started. `index` is the
+    /// number of the first sample in the stream, so the server can spot
+    /// lost batches. If the IO loop falls behind and misses slots, the
+    /// batch so far is sent early and `index` of the next one jumps ahead.
+    fn packet(&mut self) -> JsonValue {
+        // Converted for each connection as it's sent
+        let values = self.samples.iter().map(|&v| i64::from(v));
+        let samples: Vec<i64> = if self.settings.delta {
+            // The first sample as is, then the change from the one before
+            let mut last = 0;
+            values
+                .map(|v| {
+                    let delta = v - last;
+                    last = v;
+                    delta
+                })
+                .collect()
+        } else {
+            values.collect()
+        };
+
+        let offset_us = self.sent * self.settings.interval_us;
+        let Start { wall_ms, mono_us } = self.started.unwrap_or_default();
+        let mut packet = serde_json!({
+            "opcode": 0xC,
+            "mono_ms": (mono_us + offset_us) / 1000,
+            "interval_us": self.settings.interval_us,
+            "index": self.sent,
+            "delta": self.settings.delta,
+            "format": Format::Raw.name(),
+            "samples": samples
+        });
+        if let Some(wall_ms) = wall_ms {
+            packet["start"] = (wall_ms + offset_us / 1000).into();
+        }
+        self.sent += self.samples.len() as u64;
+        self.samples.clear();
+        packet
+    }
+}
+
+/// The IO loop's side of streaming: decides when the next read is due and
+/// collects the reads into batches.
+pub struct Sampler {
+    generation: u32,
+    stream: Option<Stream>,
+}
+
+impl Sampler {
+    pub fn new() -> Self {
+        Self {
+            generation: GENERATION.load(SeqCst),
+            stream: None,
+        }
+    }
+
+    /// Waits until the next read is due: the next sample while streaming,
+    /// [`runtime::loop_delay`] otherwise.
+    pub async fn wait(&mut self) {
+        let generation = GENERATION.load(SeqCst);
+        if generation != self.generation {
+            self.generation = generation;
+            self.stream = SETTINGS.lock().unwrap().map(Stream::new);
+        }
+
+        match &mut self.stream {
+            Some(stream) => {
+                let tick = stream.interval.tick().await;
+                let first = *stream.first_tick.get_or_insert(tick);
+                let elapsed_us = (tick - first).as_micros() as u64;
+                stream.slot = elapsed_us / stream.settings.interval_us;
+            }
+            None => sleep(runtime::loop_delay()).await,
+        }
+    }
+
+    /// Records a read. Returns a SAMPLES packet once a batch is full (or
+    /// cut short by skipped slots).
+    pub fn record(&mut self, value: u16) -> Option<JsonValue> {
+        let stream = self.stream.as_mut()?;
+        stream.started.get_or_insert_with(Start::now);
+
+        let mut packet = None;
+        let next = stream.sent + stream.samples.len() as u64;
+        if stream.slot > next {
+            // Samples in a batch have to be back to back
+            if !stream.samples.is_empty() {
+                packet = Some(stream.packet());
+            }
+            stream.sent = stream.slot;
+        }
+
+        stream.samples.push(value);
+        // (a batch cut short above means `batch` > 1, so this can't replace it)
+        if stream.samples.len() >= stream.settings.batch {
+            packet = Some(stream.packet());
+        }
+        packet
+    }
+}
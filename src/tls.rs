@@ -0,0 +1,226 @@
+// This file is part of cloudbit-software.
+//
+// cloudbit-software - an alternative software for the littleBits cloudBit.
+//
+// Copyright (C) 2024 littleBitsman
+//
+// cloudbit-software is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// cloudbit-software is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see https://www.gnu.org/licenses/.
+
+//! TLS settings for `wss://` (and `mqtts://`)
+//!
+//! By default the bundled `webpki-roots` are trusted. On top of that:
+//! - [`Config::tls_ca_file`] replaces them with the certificates in a PEM
+//!   file (for gateways with a private CA);
+//! - [`Config::tls_pins`] only accepts servers with one of the listed
+//!   public keys somewhere in their certificate chain (the base64 SHA-256
+//!   of the SubjectPublicKeyInfo, like `openssl ... | base64`);
+//! - if the certificate and key at [`Config::tls_client_cert`] and
+//!   [`Config::tls_client_key`] exist, they are used as a client
+//!   certificate, so the server can tell which device it's talking to.
+//!
+//! The settings are read once. If any of the files is broken, TLS
+//! connections fail (with the reason logged) rather than quietly trusting
+//! something else.
+
+use crate::config::{self, Config};
+use data_encoding::BASE64;
+use ring::digest::{digest, SHA256};
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    pki_types::{
+        pem::{Error as PemError, PemObject},
+        CertificateDer, PrivateKeyDer, ServerName, UnixTime,
+    },
+    ClientConfig, DigitallySignedStruct, Error as TlsError, RootCertStore, SignatureScheme,
+};
+use std::{
+    fmt::{Debug, Formatter, Result as FmtResult},
+    io::ErrorKind as IoErrorKind,
+    iter::once,
+    path::Path,
+    sync::{Arc, OnceLock},
+};
+use webpki::EndEntityCert;
+use webpki_roots::TLS_SERVER_ROOTS;
+
+static CLIENT_CONFIG: OnceLock<Result<Arc<ClientConfig>, String>> = OnceLock::new();
+
+/// The TLS settings for connections to servers (built the first time
+/// they're needed).
+pub fn client_config() -> Result<Arc<ClientConfig>, String> {
+    CLIENT_CONFIG
+        .get_or_init(|| {
+            build(config::get())
+                .map(Arc::new)
+                .inspect_err(|err| eprintln!("TLS settings are broken: {err}"))
+        })
+        .clone()
+}
+
+fn build(config: &Config) -> Result<ClientConfig, String> {
+    let mut roots = RootCertStore::empty();
+    match &config.tls_ca_file {
+        Some(path) => {
+            let certs = CertificateDer::pem_file_iter(path)
+                .and_then(|v| v.collect::<Result<Vec<_>, _>>())
+                .map_err(|err| format!("failed to read {path}: {err}"))?;
+            let (added, _) = roots.add_parsable_certificates(certs);
+            if added == 0 {
+                return Err(format!("no usable CA certificates in {path}"));
+            }
+        }
+        None => roots.extend(TLS_SERVER_ROOTS.iter().cloned()),
+    }
+
+    let webpki = WebPkiServerVerifier::builder(Arc::new(roots))
+        .build()
+        .map_err(|err| err.to_string())?;
+    let verifier: Arc<dyn ServerCertVerifier> = if config.tls_pins.is_empty() {
+        webpki
+    } else {
+        Arc::new(PinnedVerifier::new(webpki, &config.tls_pins)?)
+    };
+
+    let builder = ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(verifier);
+
+    let client_auth = match client_identity(config)? {
+        Some((certs, key)) => builder
+            .with_client_auth_cert(certs, key)
+            .map_err(|err| format!("bad client certificate: {err}"))?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(client_auth)
+}
+
+/// The client certificate chain and key, if both files exist.
+fn client_identity(
+    config: &Config,
+) -> Result<Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>, String> {
+    let (cert_path, key_path) = (&config.tls_client_cert, &config.tls_client_key);
+    if !Path::new(cert_path).exists() && !Path::new(key_path).exists() {
+        return Ok(None);
+    }
+
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|v| v.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| format!("failed to read {cert_path}: {err}"))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates in {cert_path}"));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|err| match err {
+        PemError::Io(err) if err.kind() == IoErrorKind::NotFound => {
+            format!("{cert_path} exists but {key_path} doesn't")
+        }
+        err => format!("failed to read {key_path}: {err}"),
+    })?;
+
+    eprintln!("using client certificate {cert_path}");
+    Ok(Some((certs, key)))
+}
+
+/// The base64 SHA-256 of a certificate's SubjectPublicKeyInfo.
+fn spki_hash(cert: &CertificateDer<'_>) -> Option<Vec<u8>> {
+    let cert = EndEntityCert::try_from(cert).ok()?;
+    Some(
+        digest(&SHA256, &cert.subject_public_key_info())
+            .as_ref()
+            .to_vec(),
+    )
+}
+
+/// Checks the certificate chain as usual, then checks that one of the
+/// certificates in it has a pinned public key.
+struct PinnedVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pins: Vec<Vec<u8>>,
+}
+
+impl PinnedVerifier {
+    fn new(inner: Arc<WebPkiServerVerifier>, pins: &[String]) -> Result<Self, String> {
+        let pins = pins
+            .iter()
+            .map(|pin| {
+                // `sha256/` is how HPKP wrote them
+                let encoded = pin.trim().trim_start_matches("sha256/");
+                match BASE64.decode(encoded.as_bytes()) {
+                    Ok(hash) if hash.len() == 32 => Ok(hash),
+                    _ => Err(format!("bad pin {pin} (expected base64 SHA-256)")),
+                }
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { inner, pins })
+    }
+}
+
+impl Debug for PinnedVerifier {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "PinnedVerifier({} pins)", self.pins.len())
+    }
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+
+        let pinned = once(end_entity)
+            .chain(intermediates)
+            .filter_map(spki_hash)
+            .any(|hash| self.pins.contains(&hash));
+        if pinned {
+            Ok(verified)
+        } else {
+            Err(TlsError::General(String::from(
+                "server certificate doesn't match any pinned key",
+            )))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
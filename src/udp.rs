@@ -0,0 +1,240 @@
+// This file is part of cloudbit-software.
+//
+// cloudbit-software - an alternative software for the littleBits cloudBit.
+//
+// Copyright (C) 2024 littleBitsman
+//
+// cloudbit-software is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// cloudbit-software is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see https://www.gnu.org/licenses/.
+
+//! UDP transport (built with the `udp` feature)
+//!
+//! For local servers where a WebSocket is more than is needed. Every
+//! datagram is one packet: JSON if it starts with `{`, MessagePack
+//! otherwise.
+//!
+//! UDP doesn't promise anything, so over UDP:
+//! - sequence numbers are always on (whatever HELLO says), so the server
+//!   can see lost INPUT packets and ask for them again with RESEND (0x5),
+//!   and late or duplicated packets from the server are dropped;
+//! - there is no connection to open. IDENTIFY registers the cloudBit with
+//!   the server and is sent again until the server answers (CHALLENGE is
+//!   answered the same way as over a WebSocket);
+//! - the heartbeat is the KEEPALIVE packet (0x7). It keeps the server's
+//!   registration (and any NAT mapping on the way) alive, and tells the
+//!   cloudBit when the server has gone away.
+
+use crate::{
+    auth::Handshake,
+    config,
+    connection::{hello_or_first, Disconnect, Identity, PacketSink, Session},
+    mdns,
+    protocol::{self, Encoded, Encoding, Features},
+};
+use futures::StreamExt;
+use libc::EMSGSIZE;
+use serde_json::{json as serde_json, Value as JsonValue};
+use std::{
+    io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{net::UdpSocket, select, time::timeout};
+use url::Url;
+
+/// How many times IDENTIFY is sent before giving up on the server
+const REGISTER_ATTEMPTS: u32 = 5;
+
+/// How long to wait for an answer to each IDENTIFY
+const REGISTER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Big enough for any datagram
+const RECEIVE_BUFFER_SIZE: usize = 65536;
+
+/// A KEEPALIVE (0x7) packet. `ping` asks for a `pong` with the same number.
+fn keepalive(key: &str, count: u64) -> JsonValue {
+    let mut packet = serde_json!({ "opcode": 0x7 });
+    packet[key] = count.into();
+    packet
+}
+
+/// Opens a socket "connected" to the server (so only its datagrams come in).
+async fn open(url: &Url) -> IoResult<UdpSocket> {
+    // `parse_url` makes sure there is a port
+    let addr = mdns::lookup(url, url.port().unwrap()).await?[0];
+
+    let local: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+    Ok(socket)
+}
+
+/// The sending half of a connection.
+pub struct UdpSender<'a> {
+    socket: &'a UdpSocket,
+    encoding: Encoding,
+}
+
+impl PacketSink for UdpSender<'_> {
+    // Keeps a backlog packet around the size of one Ethernet frame
+    const BACKLOG_BATCH_SIZE: usize = 32;
+
+    async fn send_packet(&mut self, packet: &JsonValue) -> Result<(), Disconnect> {
+        let bytes = match self.encoding.encode(packet) {
+            Encoded::Text(text) => text.into_bytes(),
+            Encoded::Binary(bytes) => bytes,
+        };
+        match self.socket.send(&bytes).await {
+            Ok(_) => Ok(()),
+            // Too big for one datagram; nothing else is wrong with the socket
+            Err(err) if err.raw_os_error() == Some(EMSGSIZE) => {
+                eprintln!(
+                    "packet too big for a datagram ({} bytes), dropped",
+                    bytes.len()
+                );
+                Ok(())
+            }
+            Err(err) => Err(Disconnect::Io(err)),
+        }
+    }
+}
+
+/// A socket the server has answered IDENTIFY on.
+pub struct UdpLink {
+    socket: UdpSocket,
+    features: Features,
+    first_packet: Option<JsonValue>,
+    /// The nonce sent in AUTH, if the server sent CHALLENGE
+    nonce: Option<String>,
+}
+
+/// Registers with the server: sends IDENTIFY until anything comes back.
+pub async fn connect(url: &Url, identity: &Identity) -> IoResult<UdpLink> {
+    let socket = open(url).await?;
+    let identify = identity.identify().to_string();
+    let mut buffer = vec![0; RECEIVE_BUFFER_SIZE];
+
+    'register: for _ in 0..REGISTER_ATTEMPTS {
+        socket.send(identify.as_bytes()).await?;
+
+        let Ok(received) = timeout(REGISTER_TIMEOUT, socket.recv(&mut buffer)).await else {
+            continue;
+        };
+        let mut packet = protocol::decode(&buffer[..received?]);
+
+        // A lost AUTH (or answer to it) starts over from IDENTIFY
+        let mut handshake = Handshake::default();
+        while let Some(answer) = packet.as_ref().and_then(|v| handshake.answer(v)) {
+            socket.send(answer.to_string().as_bytes()).await?;
+            let Ok(received) = timeout(REGISTER_TIMEOUT, socket.recv(&mut buffer)).await else {
+                continue 'register;
+            };
+            packet = protocol::decode(&buffer[..received?]);
+        }
+        let nonce = handshake
+            .finish(packet.as_ref(), config::get())
+            .map_err(|reason| IoError::new(IoErrorKind::PermissionDenied, reason))?;
+
+        let (mut features, first_packet) = hello_or_first(packet);
+        if !features.sequence {
+            eprintln!("sequence numbers are always on over UDP");
+            features.sequence = true;
+        }
+
+        return Ok(UdpLink {
+            socket,
+            features,
+            first_packet,
+            nonce,
+        });
+    }
+
+    Err(IoError::new(
+        IoErrorKind::TimedOut,
+        "no answer from server to IDENTIFY",
+    ))
+}
+
+/// Checks whether a server answers KEEPALIVE again, without registering.
+pub async fn probe(url: &Url) -> bool {
+    let attempt = async {
+        let socket = open(url).await?;
+        socket
+            .send(keepalive("ping", 0).to_string().as_bytes())
+            .await?;
+        let mut buffer = vec![0; RECEIVE_BUFFER_SIZE];
+        socket.recv(&mut buffer).await
+    };
+
+    let wait = Duration::from_millis(config::get().hello_timeout_ms);
+    matches!(timeout(wait, attempt).await, Ok(Ok(_)))
+}
+
+/// Runs one "connection" until the server stops answering.
+pub async fn run_session(link: UdpLink, mut session: Session<'_>) -> Disconnect {
+    let UdpLink {
+        socket,
+        features,
+        first_packet,
+        nonce,
+    } = link;
+    session.nonce = nonce;
+    let mut sender = UdpSender {
+        socket: &socket,
+        encoding: features.encoding,
+    };
+
+    if let Err(reason) = session.start(&mut sender, features, first_packet).await {
+        return reason;
+    }
+
+    let mut buffer = vec![0; RECEIVE_BUFFER_SIZE];
+    loop {
+        let result = select! {
+            received = socket.recv(&mut buffer) => match received {
+                Err(err) => return Disconnect::Io(err),
+                Ok(size) => match protocol::decode(&buffer[..size]) {
+                    // KEEPALIVE is handled here, it's part of the transport
+                    Some(packet) if packet["opcode"].as_u64() == Some(0x7) => {
+                        if let Some(count) = packet["pong"].as_u64() {
+                            if let Some(latency) = session.heartbeat.pong(count) {
+                                session.link.latency = Some(latency);
+                            }
+                            Ok(())
+                        } else if let Some(count) = packet["ping"].as_u64() {
+                            sender.send_packet(&keepalive("pong", count)).await
+                        } else {
+                            Ok(())
+                        }
+                    }
+                    Some(packet) => session.handle(&mut sender, packet).await,
+                    None => Ok(()),
+                },
+            },
+            Some(packet) = session.outgoing.next() => {
+                let packet = session.stamp(packet);
+                sender.send_packet(&packet).await
+            }
+            ping = session.heartbeat.next() => match ping {
+                Some(count) => sender.send_packet(&keepalive("ping", count)).await,
+                None => return Disconnect::HeartbeatTimeout,
+            },
+            () = session.fail_back.wait() => return Disconnect::FailBack,
+        };
+
+        if let Err(reason) = result {
+            return reason;
+        }
+    }
+}
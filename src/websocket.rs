@@ -0,0 +1,333 @@
+// This file is part of cloudbit-software.
+//
+// cloudbit-software - an alternative software for the littleBits cloudBit.
+//
+// Copyright (C) 2024 littleBitsman
+//
+// cloudbit-software is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// cloudbit-software is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see https://www.gnu.org/licenses/.
+
+//! WebSocket transport
+//!
+//! The default (and original) way of talking to the server: one packet per
+//! text frame (JSON) or binary frame (MessagePack), with WebSocket pings
+//! for the heartbeat. Frames can be compressed (see [`compression`]).
+
+use crate::{
+    auth::Handshake,
+    compression::{self, Compressed},
+    config,
+    connection::{hello_or_first, Disconnect, Identity, PacketSink, Session},
+    protocol::{self, Encoded, Encoding, Features},
+    proxy, tls,
+};
+use futures::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use serde_json::Value as JsonValue;
+use std::{io::ErrorKind as IoErrorKind, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    pin, select,
+    time::{sleep, Instant},
+};
+use tokio_rustls::{rustls::pki_types::ServerName, TlsConnector};
+use tokio_tungstenite::{
+    client_async,
+    tungstenite::{
+        handshake::client::{generate_key, Request},
+        Error as WebSocketError, Message,
+    },
+    MaybeTlsStream, WebSocketStream,
+};
+use url::Url;
+
+type WebSocket = WebSocketStream<Compressed<MaybeTlsStream<TcpStream>>>;
+type WebSocketSource = SplitStream<WebSocket>;
+
+impl Disconnect {
+    /// Sorts a WebSocket error into "the connection is gone" (`Some`)
+    /// or "log it and keep going" (`None`).
+    ///
+    /// Running out of memory is not something reconnecting can fix,
+    /// so that still panics.
+    fn from_error(err: WebSocketError) -> Option<Self> {
+        match err {
+            WebSocketError::AlreadyClosed | WebSocketError::ConnectionClosed => {
+                Some(Self::Closed(None))
+            }
+            WebSocketError::Io(err) => match err.kind() {
+                IoErrorKind::OutOfMemory => panic!("!! OUT OF MEMORY !!"),
+                _ => Some(Self::Io(err)),
+            },
+            WebSocketError::Protocol(err) => Some(Self::Protocol(err)),
+            e => {
+                eprintln!("error on WebSocket: {e}");
+                None
+            }
+        }
+    }
+
+    /// [`Self::from_error`] as a session result.
+    pub fn check(err: WebSocketError) -> Result<(), Self> {
+        Self::from_error(err).map_or(Ok(()), Err)
+    }
+}
+
+/// The sending half of a connection (to the server, or from a local
+/// client when `S` is a plain [`TcpStream`]).
+pub struct WebSocketSender<S = Compressed<MaybeTlsStream<TcpStream>>> {
+    tx: SplitSink<WebSocketStream<S>, Message>,
+    pub encoding: Encoding,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocketSender<S> {
+    pub fn new(tx: SplitSink<WebSocketStream<S>, Message>) -> Self {
+        Self {
+            tx,
+            encoding: Encoding::Json,
+        }
+    }
+
+    pub async fn send(&mut self, message: Message) -> Result<(), Disconnect> {
+        self.tx.send(message).await.or_else(Disconnect::check)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> PacketSink for WebSocketSender<S> {
+    const BACKLOG_BATCH_SIZE: usize = 256;
+
+    async fn send_packet(&mut self, packet: &JsonValue) -> Result<(), Disconnect> {
+        let message = match self.encoding.encode(packet) {
+            Encoded::Text(text) => Message::Text(text),
+            Encoded::Binary(bytes) => Message::Binary(bytes),
+        };
+        self.send(message).await
+    }
+}
+
+/// A connection that IDENTIFY has been sent on (and HELLO sorted out).
+pub struct WebSocketLink {
+    sender: WebSocketSender,
+    receiver: WebSocketSource,
+    features: Features,
+    first_packet: Option<JsonValue>,
+    /// The nonce sent in AUTH, if the server sent CHALLENGE
+    nonce: Option<String>,
+}
+
+/// Builds the handshake request. A fresh one is made for every attempt so
+/// each gets its own `Sec-Websocket-Key`.
+fn request(url: &Url, identity: &Identity) -> Request {
+    let mut request = Request::get(url.as_str())
+        .header("MAC-Address", &identity.mac_address)
+        .header("CB-Id", &identity.cb_id)
+        .header("User-Agent", "littleARCH cloudBit")
+        .header("Host", url.host_str().unwrap())
+        .header("Connection", "Upgrade")
+        .header("Upgrade", "websocket")
+        .header("Sec-Websocket-Version", "13")
+        .header("Sec-Websocket-Key", generate_key());
+    if let Some(offer) = compression::offer() {
+        request = request.header("Sec-WebSocket-Extensions", offer);
+    }
+    request.body(()).unwrap()
+}
+
+/// Opens a WebSocket to `url` (through a proxy if there is one).
+///
+/// TLS is done here rather than by tokio-tungstenite, so that
+/// [`Compressed`] can go between it and the WebSocket.
+async fn open(url: &Url, identity: &Identity) -> Result<WebSocket, String> {
+    let port = url.port_or_known_default().unwrap_or(80);
+    let stream = proxy::connect(url, port)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    let stream = match url.scheme() {
+        "wss" => {
+            let host = url.host_str().unwrap().trim_matches(['[', ']']);
+            let name = ServerName::try_from(host.to_string()).map_err(|err| err.to_string())?;
+            let tls = TlsConnector::from(tls::client_config()?)
+                .connect(name, stream)
+                .await
+                .map_err(|err| err.to_string())?;
+            MaybeTlsStream::Rustls(tls)
+        }
+        _ => MaybeTlsStream::Plain(stream),
+    };
+
+    let (client, _) = client_async(request(url, identity), Compressed::new(stream))
+        .await
+        .map_err(|err| err.to_string())?;
+    Ok(client)
+}
+
+/// Opens a connection, sends IDENTIFY and waits for HELLO.
+pub async fn connect(url: &Url, identity: &Identity) -> Result<WebSocketLink, String> {
+    let client = open(url, identity).await?;
+    let (tx, mut receiver) = client.split();
+
+    // IDENTIFY always goes out as JSON, the encoding isn't picked yet
+    let mut sender = WebSocketSender::new(tx);
+    sender
+        .send_packet(&identity.identify())
+        .await
+        .map_err(|reason| reason.to_string())?;
+
+    let (features, first_packet, nonce) = await_hello(&mut sender, &mut receiver)
+        .await
+        .map_err(|reason| reason.to_string())?;
+    sender.encoding = features.encoding;
+
+    Ok(WebSocketLink {
+        sender,
+        receiver,
+        features,
+        first_packet,
+        nonce,
+    })
+}
+
+/// Checks whether a server accepts connections again, without staying
+/// connected to it.
+pub async fn probe(url: &Url, identity: &Identity) -> bool {
+    match open(url, identity).await {
+        Ok(mut client) => {
+            let _ = client.close(None).await;
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Runs one connection until it drops.
+pub async fn run_session(link: WebSocketLink, mut session: Session<'_>) -> Disconnect {
+    let WebSocketLink {
+        mut sender,
+        mut receiver,
+        features,
+        first_packet,
+        nonce,
+    } = link;
+
+    session.nonce = nonce;
+    if let Err(reason) = session.start(&mut sender, features, first_packet).await {
+        return reason;
+    }
+
+    loop {
+        // Replies are sent straight through `sender` instead of the
+        // channel; this loop is the only thing draining `outgoing`, so
+        // waiting on a full channel from in here would never finish.
+        let result = select! {
+            msg = receiver.next() => match msg {
+                None => return Disconnect::Closed(None),
+                Some(Ok(Message::Close(frame))) => {
+                    return Disconnect::Closed(frame.map(|v| v.to_string()))
+                }
+                Some(Ok(Message::Ping(data))) => sender.send(Message::Pong(data)).await,
+                Some(Ok(Message::Pong(data))) => {
+                    let latency = data
+                        .try_into()
+                        .ok()
+                        .and_then(|v| session.heartbeat.pong(u64::from_be_bytes(v)));
+                    if latency.is_some() {
+                        session.link.latency = latency;
+                    }
+                    Ok(())
+                }
+                Some(Ok(Message::Text(data))) => match protocol::decode_text(&data) {
+                    Some(packet) => session.handle(&mut sender, packet).await,
+                    None => Ok(()),
+                },
+                Some(Ok(Message::Binary(data))) => match protocol::decode_binary(&data) {
+                    Some(packet) => session.handle(&mut sender, packet).await,
+                    None => Ok(()),
+                },
+                Some(Ok(_)) => {
+                    eprintln!("unknown content");
+                    Ok(())
+                }
+                Some(Err(err)) => Disconnect::check(err),
+            },
+            Some(packet) = session.outgoing.next() => {
+                let packet = session.stamp(packet);
+                sender.send_packet(&packet).await
+            }
+            ping = session.heartbeat.next() => match ping {
+                Some(count) => sender.send(Message::Ping(count.to_be_bytes().to_vec())).await,
+                None => return Disconnect::HeartbeatTimeout,
+            },
+            () = session.fail_back.wait() => return Disconnect::FailBack,
+        };
+
+        if let Err(reason) = result {
+            return reason;
+        }
+    }
+}
+
+/// Waits (up to [`config::Config::hello_timeout_ms`]) for the server to
+/// answer IDENTIFY with HELLO, answering CHALLENGE on the way (which
+/// restarts the wait).
+///
+/// If the server sends some other packet first, or nothing at all in time,
+/// it doesn't know about HELLO and gets [`Features::LEGACY`]. That other
+/// packet is returned so it can still be handled.
+async fn await_hello(
+    sender: &mut WebSocketSender,
+    receiver: &mut WebSocketSource,
+) -> Result<(Features, Option<JsonValue>, Option<String>), Disconnect> {
+    let config = config::get();
+    let timeout = Duration::from_millis(config.hello_timeout_ms);
+    let mut handshake = Handshake::default();
+    let deadline = sleep(timeout);
+    pin!(deadline);
+
+    let packet = loop {
+        let msg = select! {
+            () = &mut deadline => break None,
+            msg = receiver.next() => msg,
+        };
+
+        let packet = match msg {
+            None => return Err(Disconnect::Closed(None)),
+            Some(Ok(Message::Close(frame))) => {
+                return Err(Disconnect::Closed(frame.map(|v| v.to_string())))
+            }
+            Some(Ok(Message::Text(data))) => protocol::decode_text(&data),
+            Some(Ok(Message::Binary(data))) => protocol::decode_binary(&data),
+            // pings are answered by tungstenite on its own
+            Some(Ok(_)) => continue,
+            Some(Err(err)) => {
+                Disconnect::check(err)?;
+                continue;
+            }
+        };
+
+        match packet.as_ref().and_then(|v| handshake.answer(v)) {
+            Some(answer) => {
+                sender.send_packet(&answer).await?;
+                deadline.as_mut().reset(Instant::now() + timeout);
+            }
+            None => break packet,
+        }
+    };
+
+    let nonce = handshake
+        .finish(packet.as_ref(), config)
+        .map_err(Disconnect::Unauthenticated)?;
+    let (features, first_packet) = hello_or_first(packet);
+    Ok((features, first_packet, nonce))
+}